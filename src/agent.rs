@@ -0,0 +1,102 @@
+use std::{collections::HashMap, fs, sync::Arc};
+
+use arc_swap::ArcSwap;
+use reqwest::{header::AUTHORIZATION, Certificate, Client, Identity};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use utoipa::ToSchema;
+
+use crate::{config::Agent, error::Error, storage::Device};
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RelayPayload {
+    pub mac: String,
+    pub broadcast_addr: String,
+    /// Interface on the agent IPv6 packets are sent on
+    pub interface: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RelayResponse {
+    /// Bytes sent by the agent
+    pub sent: usize,
+}
+
+/// HTTP clients of the configured agents, built when the config is loaded or reloaded
+#[derive(Default)]
+pub struct Agents {
+    clients: ArcSwap<HashMap<String, Client>>,
+}
+
+impl Agents {
+    pub fn load(agents: &[Agent]) -> Self {
+        let this = Self::default();
+        this.reload(agents);
+        this
+    }
+
+    /// Agents whose certificates can't be loaded are left out, their client is built on use
+    pub fn reload(&self, agents: &[Agent]) {
+        let mut clients = HashMap::new();
+        for agent in agents {
+            match client(agent) {
+                Ok(client) => {
+                    clients.insert(agent.name.clone(), client);
+                }
+                Err(err) => error!(agent = agent.name, "couldn't build agent client: {err}"),
+            }
+        }
+        self.clients.store(Arc::new(clients));
+    }
+
+    /// Client of `agent`, built again if it couldn't be loaded so the request gets the error
+    pub async fn client(&self, agent: &Agent) -> Result<Client, Error> {
+        if let Some(client) = self.clients.load().get(&agent.name) {
+            return Ok(client.clone());
+        }
+        let agent = agent.clone();
+        tokio::task::spawn_blocking(move || client(&agent)).await?
+    }
+}
+
+/// Asks `agent` to send the magic packet for `device` in its subnet
+pub async fn wake(agent: &Agent, client: &Client, device: &Device) -> Result<RelayResponse, Error> {
+    info!(agent = agent.name, "relay start of {}", device.id);
+
+    let mut request = client
+        .post(format!("{}/relay", agent.url.trim_end_matches('/')))
+        .json(&RelayPayload {
+            mac: device.mac.to_string(),
+            broadcast_addr: device.broadcast()?.to_string(),
+            interface: device.interface.clone(),
+        });
+    if let Some(secret) = &agent.secret {
+        request = request.header(AUTHORIZATION, secret);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Error::AgentResponse {
+            agent: agent.name.clone(),
+            status: status.as_u16(),
+        });
+    }
+
+    let response: RelayResponse = response.json().await?;
+    debug!(agent = agent.name, sent = response.sent, "relay successful");
+    Ok(response)
+}
+
+fn client(agent: &Agent) -> Result<Client, Error> {
+    let mut builder = Client::builder();
+    if let Some(ca) = &agent.ca {
+        builder = builder.add_root_certificate(Certificate::from_pem(&fs::read(ca)?)?);
+    }
+    if let (Some(cert), Some(key)) = (&agent.cert, &agent.key) {
+        let mut pem = fs::read(cert)?;
+        pem.extend(fs::read(key)?);
+        builder = builder.identity(Identity::from_pem(&pem)?);
+    }
+    Ok(builder.build()?)
+}
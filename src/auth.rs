@@ -1,26 +1,28 @@
-use crate::AppState;
+use crate::{tls::ClientCert, AppState};
 use axum::{
     extract::{Request, State},
     http::{HeaderMap, StatusCode},
     middleware::Next,
     response::Response,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use tracing::trace;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Methods {
     Key,
+    Mtls,
     None,
 }
 
 pub async fn auth(
-    State(state): State<AppState>,
+    State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     request: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    let auth = state.config.auth;
+    let auth = state.config.load().auth.clone();
     trace!(?auth.method, "auth request");
     match auth.method {
         Methods::Key => {
@@ -37,6 +39,15 @@ pub async fn auth(
                 Err(StatusCode::UNAUTHORIZED)
             }
         }
+        Methods::Mtls => {
+            if request.extensions().get::<ClientCert>().is_some() {
+                trace!("auth successfull");
+                Ok(next.run(request).await)
+            } else {
+                trace!("auth failed, no client certificate");
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        }
         Methods::None => Ok(next.run(request).await),
     }
 }
@@ -0,0 +1,309 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2::{self, PBKDF2_HMAC_SHA512},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::config;
+use crate::error::Error;
+use crate::hex;
+use crate::storage::{Device, DeviceId};
+
+/// Archive format written by this version, older formats can still be restored
+pub const VERSION: u32 = 1;
+
+/// First word of the header line
+const MAGIC: &str = "webol-backup";
+
+/// Archives with other counts are rejected, the count is read before the header is authenticated
+const KDF_ITERATIONS: u32 = 210_000;
+
+const SALT_LEN: usize = 16;
+
+const KEY_LEN: usize = 32;
+
+/// Everything needed to bring a webol instance back to the state of [`Archive::created`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Archive {
+    /// Format of the archive, see [`VERSION`]
+    pub version: u32,
+    /// webol version that wrote the archive
+    pub webol: String,
+    /// Unix timestamp in seconds
+    pub created: u64,
+    /// Device files as stored, keyed by id, boot history included
+    pub devices: BTreeMap<String, Value>,
+    /// Contents of the config files that existed, keyed by file name
+    pub config: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Restored {
+    /// webol version that wrote the archive
+    pub webol: String,
+    /// Unix timestamp the archive was created at
+    pub created: u64,
+    pub devices: usize,
+    /// Devices that weren't part of the archive and were removed
+    pub deleted: usize,
+    /// Restored config files
+    pub config: Vec<String>,
+}
+
+/// Collects devices and config files into an archive, encrypted if a passphrase is given
+pub fn create(passphrase: Option<&str>) -> Result<Vec<u8>, Error> {
+    let mut devices = BTreeMap::new();
+    {
+        let _lock = Device::lock();
+        for (id, path) in device_files()? {
+            match serde_json::from_str(&fs::read_to_string(&path)?) {
+                Ok(device) => {
+                    devices.insert(id.to_string(), device);
+                }
+                Err(err) => warn!(
+                    ?path,
+                    "device file isn't valid json and isn't backed up: {err}"
+                ),
+            }
+        }
+    }
+
+    let mut config = BTreeMap::new();
+    for file in config::FILES {
+        if Path::new(file).is_file() {
+            config.insert(file.to_string(), fs::read_to_string(file)?);
+        }
+    }
+
+    let archive = Archive {
+        version: VERSION,
+        webol: env!("CARGO_PKG_VERSION").to_string(),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs()),
+        devices,
+        config,
+    };
+    info!(
+        devices = archive.devices.len(),
+        encrypted = passphrase.is_some(),
+        "create backup"
+    );
+
+    let body = serde_json::to_vec(&archive)?;
+    match passphrase {
+        Some(passphrase) => encrypt(body, passphrase),
+        None => Ok([format!("{MAGIC} {VERSION} plain\n").into_bytes(), body].concat()),
+    }
+}
+
+/// Reads an archive, the header line tells the format version and how it is encrypted
+pub fn open(data: &[u8], passphrase: Option<&str>) -> Result<Archive, Error> {
+    let split = data
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or_else(|| invalid("missing header"))?;
+    let (header, body) = (&data[..split], &data[split + 1..]);
+    let header = std::str::from_utf8(header).map_err(|_| invalid("invalid header"))?;
+
+    let fields = header.split(' ').collect::<Vec<_>>();
+    let (version, encryption) = match fields.as_slice() {
+        [MAGIC, version, encryption @ ..] => (
+            version
+                .parse::<u32>()
+                .map_err(|_| invalid("invalid version"))?,
+            encryption,
+        ),
+        _ => return Err(invalid("not a webol backup")),
+    };
+    if version > VERSION {
+        return Err(Error::UnsupportedBackup { version });
+    }
+
+    let body = match encryption {
+        ["plain"] => body.to_vec(),
+        [kdf, cipher] => {
+            let passphrase = passphrase.ok_or(Error::BackupPassphrase)?;
+            decrypt(header, kdf, cipher, body, passphrase)?
+        }
+        _ => return Err(invalid("unknown encryption")),
+    };
+
+    let archive: Archive = serde_json::from_slice(&body)?;
+    info!(
+        version = archive.version,
+        webol = archive.webol,
+        created = archive.created,
+        "opened backup"
+    );
+    Ok(archive)
+}
+
+/// Replaces all devices, and the config files if `restore_config` is set, with the archive's.
+///
+/// Every device is checked and written to a temporary file before the stored ones are replaced,
+/// devices that aren't in the archive are deleted afterwards. If a delete fails, the archive's
+/// devices are restored but some of the other devices are left.
+pub fn restore(archive: Archive, restore_config: bool) -> Result<Restored, Error> {
+    let mut devices = vec![];
+    for (id, device) in archive.devices {
+        let id = DeviceId::new(id)?;
+        let device = Device::from_stored(&id, device)
+            .map_err(|err| invalid(&format!("device {id}: {err}")))?;
+        devices.push(device);
+    }
+
+    let mut restored = Restored {
+        webol: archive.webol,
+        created: archive.created,
+        devices: devices.len(),
+        deleted: 0,
+        config: vec![],
+    };
+
+    {
+        let _lock = Device::lock();
+        Device::write_all(&devices)?;
+        // Listed by name, broken files are removed like the others
+        for (id, _) in device_files()? {
+            if !devices.iter().any(|device| device.id == id) {
+                Device::delete(&id)?;
+                restored.deleted += 1;
+            }
+        }
+    }
+
+    if restore_config {
+        for (file, contents) in archive.config {
+            if !config::FILES.contains(&file.as_str()) {
+                warn!(file, "unknown config file in backup is skipped");
+                continue;
+            }
+            let tmp = format!("{file}.tmp");
+            fs::write(&tmp, contents)?;
+            fs::rename(&tmp, &file)?;
+            restored.config.push(file);
+        }
+    }
+
+    info!(
+        devices = restored.devices,
+        deleted = restored.deleted,
+        config = ?restored.config,
+        "restored backup"
+    );
+    Ok(restored)
+}
+
+/// Device files by id, files with invalid names are skipped
+fn device_files() -> Result<Vec<(DeviceId, PathBuf)>, Error> {
+    let mut files = vec![];
+    for entry in fs::read_dir(Device::storage())? {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new("json")) {
+            continue;
+        }
+        let Some(id) = path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|id| DeviceId::new(id).ok())
+        else {
+            warn!(?path, "device file with invalid name is skipped");
+            continue;
+        };
+        files.push((id, path));
+    }
+    Ok(files)
+}
+
+fn encrypt(mut body: Vec<u8>, passphrase: &str) -> Result<Vec<u8>, Error> {
+    let rng = SystemRandom::new();
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    rng.fill(&mut salt).map_err(|_| invalid("no randomness"))?;
+    rng.fill(&mut nonce).map_err(|_| invalid("no randomness"))?;
+
+    let header = format!(
+        "{MAGIC} {VERSION} pbkdf2-sha512:{KDF_ITERATIONS}:{} chacha20-poly1305:{}",
+        hex::encode(&salt),
+        hex::encode(&nonce)
+    );
+    // The header is authenticated, so the version can't be changed without the passphrase
+    key(passphrase, &salt, KDF_ITERATIONS)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(header.as_bytes()),
+            &mut body,
+        )
+        .map_err(|_| invalid("encryption failed"))?;
+
+    Ok([format!("{header}\n").into_bytes(), body].concat())
+}
+
+fn decrypt(
+    header: &str,
+    kdf: &str,
+    cipher: &str,
+    body: &[u8],
+    passphrase: &str,
+) -> Result<Vec<u8>, Error> {
+    let (iterations, salt) = match kdf.split(':').collect::<Vec<_>>().as_slice() {
+        ["pbkdf2-sha512", iterations, salt] => (
+            iterations
+                .parse::<u32>()
+                .ok()
+                .filter(|iterations| *iterations == KDF_ITERATIONS)
+                .ok_or_else(|| invalid("unsupported key derivation iterations"))?,
+            unhex(salt)?,
+        ),
+        _ => return Err(invalid("unknown key derivation")),
+    };
+    let nonce = match cipher.split_once(':') {
+        Some(("chacha20-poly1305", nonce)) => Nonce::try_assume_unique_for_key(&unhex(nonce)?)
+            .map_err(|_| invalid("invalid nonce"))?,
+        _ => return Err(invalid("unknown cipher")),
+    };
+
+    let mut body = body.to_vec();
+    let plain = key(passphrase, &salt, iterations)?
+        .open_in_place(nonce, Aad::from(header.as_bytes()), &mut body)
+        .map_err(|_| Error::BackupDecrypt)?;
+    Ok(plain.to_vec())
+}
+
+fn key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey, Error> {
+    let iterations = NonZeroU32::new(iterations).ok_or_else(|| invalid("invalid iterations"))?;
+    let mut key = [0; KEY_LEN];
+    pbkdf2::derive(
+        PBKDF2_HMAC_SHA512,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key).map_err(|_| invalid("invalid key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBackup {
+        message: message.to_string(),
+    }
+}
+
+fn unhex(hex: &str) -> Result<Vec<u8>, Error> {
+    hex::decode(hex).ok_or_else(|| invalid("invalid hex"))
+}
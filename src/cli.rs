@@ -0,0 +1,192 @@
+use crate::backup;
+use crate::config::Config;
+use crate::error::Error;
+use crate::services::listen;
+use crate::storage::Device;
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::Result;
+use notify::{RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// Validate the config and every device file, then exit
+    CheckConfig,
+    /// Print received magic packets instead of starting the server
+    Listen {
+        /// UDP port to listen on, can be repeated, defaults to `listen.ports` or 7 and 9
+        #[arg(short, long)]
+        port: Vec<u16>,
+        /// Also capture ethernet frames with ethertype 0x0842, needs `CAP_NET_RAW`
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Write devices, boot history and config into one archive
+    Backup {
+        /// Defaults to `webol-backup-<timestamp>.webol`
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Encrypt the archive with this passphrase
+        #[arg(long, env = "WEBOL_BACKUP_PASSPHRASE", hide_env_values = true)]
+        passphrase: Option<String>,
+    },
+    /// Replace all devices and the config with the contents of an archive
+    Restore {
+        file: PathBuf,
+        /// Passphrase the archive was encrypted with
+        #[arg(long, env = "WEBOL_BACKUP_PASSPHRASE", hide_env_values = true)]
+        passphrase: Option<String>,
+        /// Only restore the devices, keep the current config
+        #[arg(long)]
+        no_config: bool,
+    },
+}
+
+/// Prints a report for the config and all device files, returns `false` if anything is invalid
+pub fn check_config() -> Result<bool> {
+    let mut failed = false;
+
+    match Config::load() {
+        Ok(config) => {
+            println!("config: ok");
+            println!("{}", serde_json::to_string_pretty(&config.redacted())?);
+        }
+        Err(Error::InvalidConfig { errors }) => {
+            failed = true;
+            println!("config: invalid");
+            for error in errors {
+                println!("  {error}");
+            }
+        }
+        Err(err) => {
+            failed = true;
+            println!("config: {err}");
+        }
+    }
+
+    if Device::storage().exists() {
+        let devices = Device::check_all()?;
+        println!("devices: {} file(s)", devices.len());
+        for (path, device) in devices {
+            let name = path.display();
+            match device {
+                Ok(device) => {
+                    if path
+                        .file_stem()
+                        .is_some_and(|stem| stem != device.id.as_str())
+                    {
+                        failed = true;
+                        println!("  {name}: id \"{}\" doesn't match the file name", device.id);
+                    } else {
+                        println!("  {name}: ok");
+                    }
+                }
+                Err(err) => {
+                    failed = true;
+                    println!("  {name}: {err}");
+                }
+            }
+        }
+
+        let invalid = Device::invalid_files()?;
+        if !invalid.is_empty() {
+            failed = true;
+            println!("invalid device file names: {} file(s)", invalid.len());
+            for (path, suggestion) in invalid {
+                match suggestion {
+                    Some(id) => println!(
+                        "  {}: rename to {id}.json and set the id to \"{id}\"",
+                        path.display()
+                    ),
+                    None => println!("  {}: choose a new id", path.display()),
+                }
+            }
+        }
+    } else {
+        println!(
+            "devices: storage \"{}\" doesn't exist yet",
+            Device::storage().display()
+        );
+    }
+
+    Ok(!failed)
+}
+
+/// Prints every received magic packet until the process is stopped
+pub async fn listen(ports: Vec<u16>, raw: bool) -> Result<()> {
+    let mut config = Config::load()
+        .ok()
+        .and_then(|config| config.listen)
+        .unwrap_or_default();
+    if !ports.is_empty() {
+        config.ports = ports;
+    }
+    config.raw |= raw;
+
+    // Devices registered while listening are looked up as well
+    let _watcher = notify::recommended_watcher(|_| Device::changed()).and_then(|mut watcher| {
+        watcher.watch(Device::storage(), RecursiveMode::NonRecursive)?;
+        Ok(watcher)
+    });
+    println!(
+        "listening on udp {:?}{}",
+        config.ports,
+        if config.raw { " and raw frames" } else { "" }
+    );
+    listen::run(&config, |packet| {
+        let device = packet.device.as_deref().unwrap_or("unknown device");
+        println!(
+            "{} {} -> {} ({device}) via {}",
+            packet.received, packet.source, packet.mac, packet.transport
+        );
+    })
+    .await?;
+    Ok(())
+}
+
+pub fn backup(output: Option<PathBuf>, passphrase: Option<&str>) -> Result<()> {
+    let output = output.unwrap_or_else(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        PathBuf::from(format!("webol-backup-{now}.webol"))
+    });
+
+    // A host without devices yet gets an empty archive
+    Device::setup()?;
+    let archive = backup::create(passphrase)?;
+    std::fs::write(&output, archive)?;
+
+    let encrypted = if passphrase.is_some() {
+        "encrypted "
+    } else {
+        ""
+    };
+    println!("wrote {encrypted}backup to {}", output.display());
+    Ok(())
+}
+
+pub fn restore(file: &Path, passphrase: Option<&str>, config: bool) -> Result<()> {
+    let archive = backup::open(&std::fs::read(file)?, passphrase)?;
+    Device::setup()?;
+    let restored = backup::restore(archive, config)?;
+
+    println!(
+        "restored backup of webol {} created at {}",
+        restored.webol, restored.created
+    );
+    println!(
+        "devices: {} restored, {} removed",
+        restored.devices, restored.deleted
+    );
+    println!("config: {:?}", restored.config);
+    Ok(())
+}
@@ -0,0 +1,289 @@
+//! Typed async client for the webol HTTP API
+
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use reqwest::{header::AUTHORIZATION, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{
+        self, client::IntoClientRequest, http::HeaderValue, protocol::frame::coding::CloseCode,
+        Message,
+    },
+    Connector, MaybeTlsStream, WebSocketStream,
+};
+use tracing::{debug, trace};
+
+use crate::storage::{Device, DeviceId};
+
+pub use crate::routes::{device::DPayload, sessions::Session, start::Response as StartResponse};
+pub use crate::services::ping::{Milestone, Progress, SessionState};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("request: {source}")]
+    Request {
+        #[from]
+        source: reqwest::Error,
+    },
+
+    #[error("server answered with {status}: {message}")]
+    Server { status: u16, message: String },
+
+    #[error("websocket: {source}")]
+    Websocket { source: Box<tungstenite::Error> },
+
+    /// The server closed the `/status` websocket because of an error
+    #[error("status closed with {code}: {reason}")]
+    Closed { code: u16, reason: String },
+}
+
+// Boxed, the websocket error is larger than the other variants together
+impl From<tungstenite::Error> for Error {
+    fn from(source: tungstenite::Error) -> Self {
+        Self::Websocket {
+            source: Box::new(source),
+        }
+    }
+}
+
+/// Connection to a webol server, cheap to clone
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    /// TLS config of the `/status` websocket, webpki roots are used without one
+    tls: Option<Arc<ClientConfig>>,
+    base: String,
+    secret: Option<String>,
+}
+
+impl Client {
+    /// Client for the server at `base`, e.g. `http://localhost:7229`
+    pub fn new(base: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            tls: None,
+            base: base.into().trim_end_matches('/').to_string(),
+            secret: None,
+        }
+    }
+
+    /// Sent as `Authorization` header, needed if the server uses key authentication
+    pub fn with_secret(self, secret: impl Into<String>) -> Self {
+        Self {
+            secret: Some(secret.into()),
+            ..self
+        }
+    }
+
+    /// Uses `tls` for requests and the `/status` websocket, e.g. with a private CA or a client
+    /// certificate for mTLS
+    pub fn with_tls(self, tls: ClientConfig) -> Result<Self, Error> {
+        // The websocket upgrade only exists in HTTP/1.1
+        let mut websocket = tls.clone();
+        websocket.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Self {
+            http: reqwest::Client::builder()
+                .use_preconfigured_tls(tls)
+                .build()?,
+            tls: Some(Arc::new(websocket)),
+            ..self
+        })
+    }
+
+    pub async fn device(&self, id: &DeviceId) -> Result<Device, Error> {
+        let request = self.http.get(self.url(&format!("/device/{id}")));
+        self.send(request).await
+    }
+
+    /// All devices, without filters
+    pub async fn devices(&self) -> Result<Vec<Device>, Error> {
+        self.send(self.http.get(self.url("/devices"))).await
+    }
+
+    /// Adds a new device, fails with a `409` if the id is taken
+    pub async fn create(&self, device: &DPayload) -> Result<Device, Error> {
+        self.send(self.http.put(self.url("/device")).json(device))
+            .await
+    }
+
+    /// Replaces the device `id`, the id of `device` is ignored
+    pub async fn update(&self, id: &DeviceId, device: &DPayload) -> Result<Device, Error> {
+        let request = self
+            .http
+            .put(self.url(&format!("/device/{id}")))
+            .json(device);
+        self.send(request).await
+    }
+
+    /// Sends the magic packet, with `ping` the returned uuid can be passed to [`Client::status`]
+    pub async fn start(&self, id: &DeviceId, ping: bool) -> Result<StartResponse, Error> {
+        let request = self
+            .http
+            .post(self.url(&format!("/start/{id}")))
+            .json(&json!({ "ping": ping }));
+        self.send(request).await
+    }
+
+    /// Running ping sessions and recently finished ones
+    pub async fn sessions(&self) -> Result<Vec<Session>, Error> {
+        self.send(self.http.get(self.url("/sessions"))).await
+    }
+
+    pub async fn session(&self, uuid: &str) -> Result<Session, Error> {
+        self.send(self.http.get(self.url(&format!("/sessions/{uuid}"))))
+            .await
+    }
+
+    /// Stops probing the device of the session and removes it
+    pub async fn cancel(&self, uuid: &str) -> Result<(), Error> {
+        self.request(self.http.delete(self.url(&format!("/sessions/{uuid}"))))
+            .await?;
+        Ok(())
+    }
+
+    /// Follows a start with ping on the `/status` websocket
+    pub async fn status(&self, uuid: &str) -> Result<StatusStream, Error> {
+        self.watch("/status", uuid).await
+    }
+
+    /// Like [`Client::status`], with a [`Status::Progress`] after every probe round
+    pub async fn progress(&self, uuid: &str) -> Result<StatusStream, Error> {
+        self.watch("/status?progress=true", uuid).await
+    }
+
+    async fn watch(&self, path: &str, uuid: &str) -> Result<StatusStream, Error> {
+        let url = self.url(path).replacen("http", "ws", 1);
+        let mut request = url.into_client_request()?;
+        if let Some(secret) = &self.secret {
+            let secret = HeaderValue::from_str(secret)
+                .map_err(|err| tungstenite::Error::HttpFormat(err.into()))?;
+            request.headers_mut().insert(AUTHORIZATION, secret);
+        }
+
+        let connector = self.tls.clone().map(Connector::Rustls);
+        let (mut socket, _) =
+            connect_async_tls_with_config(request, None, false, connector).await?;
+        socket.send(Message::Text(uuid.to_string())).await?;
+        debug!(uuid, "watching status");
+        Ok(StatusStream {
+            socket,
+            uuid: uuid.to_string(),
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base)
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, Error> {
+        Ok(self.request(request).await?.json().await?)
+    }
+
+    /// Sends `request` with the secret, answers with an error status are turned into errors
+    async fn request(&self, mut request: RequestBuilder) -> Result<Response, Error> {
+        if let Some(secret) = &self.secret {
+            request = request.header(AUTHORIZATION, secret);
+        }
+        check(request.send().await?).await
+    }
+}
+
+/// Answers with an error status are turned into [`Error::Server`] with the message of the body
+async fn check(response: Response) -> Result<Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    trace!(%status, body, "server answered with an error");
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|body| {
+            // `Problem` details have a `detail`, all other errors an `error`
+            body.get("detail")
+                .or_else(|| body.get("error"))
+                .and_then(Value::as_str)
+                .map(String::from)
+        })
+        .unwrap_or(body);
+    Err(Error::Server {
+        status: status.as_u16(),
+        message,
+    })
+}
+
+/// Message of the `/status` websocket, see [`crate::routes::status::status`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// Average boot time of the device in seconds, sent first
+    Eta(u64),
+    /// Sent by [`Client::progress`] streams after every probe round and before the result
+    Progress(Progress),
+    /// The device passed every check of `pingprobes`
+    Online,
+    /// The device didn't answer within `pingtimeout`
+    Timeout,
+    /// Pinging the device failed
+    Error,
+    /// The session was cancelled with [`Client::cancel`]
+    Cancelled,
+    /// The server doesn't know the uuid
+    NotFound,
+}
+
+impl Status {
+    /// Parses `<kind>_<uuid>`, `eta_<seconds>_<uuid>` and `progress_<json>_<uuid>`, `None` for
+    /// other messages
+    pub fn parse(message: &str, uuid: &str) -> Option<Self> {
+        let kind = message.strip_suffix(uuid)?.strip_suffix('_')?;
+        match kind {
+            "start" => Some(Self::Online),
+            "timeout" => Some(Self::Timeout),
+            "error" => Some(Self::Error),
+            "cancelled" => Some(Self::Cancelled),
+            "notfound" => Some(Self::NotFound),
+            _ => match kind.strip_prefix("progress_") {
+                Some(progress) => serde_json::from_str(progress).ok().map(Self::Progress),
+                None => kind.strip_prefix("eta_")?.parse().ok().map(Self::Eta),
+            },
+        }
+    }
+}
+
+/// Status messages of one start, see [`Client::status`]
+pub struct StatusStream {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    uuid: String,
+}
+
+impl StatusStream {
+    /// Next status, `None` once the server closed the connection normally
+    pub async fn next(&mut self) -> Result<Option<Status>, Error> {
+        while let Some(message) = self.socket.next().await {
+            match message? {
+                Message::Text(text) => {
+                    if let Some(status) = Status::parse(&text, &self.uuid) {
+                        return Ok(Some(status));
+                    }
+                    debug!(text, "unknown status message");
+                }
+                Message::Close(Some(frame)) if frame.code != CloseCode::Normal => {
+                    return Err(Error::Closed {
+                        code: frame.code.into(),
+                        reason: frame.reason.into_owned(),
+                    });
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+}
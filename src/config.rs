@@ -1,36 +1,504 @@
+use std::{
+    fmt::Display,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+};
+
 use config::File;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
 
 use crate::auth;
+use crate::error::{Error, FieldError};
+
+/// Config files in the order they are merged, later files override earlier ones
+pub const FILES: [&str; 2] = ["config.toml", "config.dev.toml"];
+
+const REDACTED: &str = "<redacted>";
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub serveraddr: String,
     pub pingtimeout: i64,
     pub pingthreshold: u64,
+    /// Seconds finished ping sessions are kept, e.g. for `GET /sessions`
+    #[serde(default = "default_retention")]
+    pub pingretention: u64,
+    /// Checks of booting devices, each one reached is a milestone, online once all are reached
+    #[serde(default = "default_probes")]
+    pub pingprobes: Vec<Check>,
+    pub loglevel: String,
     pub auth: Auth,
+    pub tls: Option<Tls>,
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
+    /// Accept wake requests relayed from other webol instances
+    #[serde(default)]
+    pub relay: bool,
+    #[serde(default)]
+    pub agents: Vec<Agent>,
+    /// IEEE OUI list used for vendor lookups during discovery
+    pub ouifile: Option<String>,
+    /// Receive magic packets in the background, see `webol listen`
+    pub listen: Option<Listen>,
+    #[serde(default)]
+    pub ratelimit: RateLimit,
+    #[serde(default)]
+    pub wake: Wake,
+}
+
+/// How a booting device is checked, written as `"arp"`, `"icmp"` or `"tcp:<port>"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Check {
+    /// The device is in the ARP cache of the server, IPv4 only
+    Arp,
+    /// The device answers pings
+    Icmp,
+    /// The port accepts connections, e.g. 22 once SSH is up
+    Tcp(u16),
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Arp => f.write_str("arp"),
+            Self::Icmp => f.write_str("icmp"),
+            Self::Tcp(port) => write!(f, "tcp:{port}"),
+        }
+    }
+}
+
+impl TryFrom<String> for Check {
+    type Error = String;
+
+    fn try_from(check: String) -> Result<Self, Self::Error> {
+        match check.as_str() {
+            "arp" => Ok(Self::Arp),
+            "icmp" => Ok(Self::Icmp),
+            _ => check
+                .strip_prefix("tcp:")
+                .and_then(|port| port.parse().ok())
+                .map(Self::Tcp)
+                .ok_or_else(|| format!("\"{check}\" isn't \"arp\", \"icmp\" or \"tcp:<port>\"")),
+        }
+    }
+}
+
+impl From<Check> for String {
+    fn from(check: Check) -> Self {
+        check.to_string()
+    }
+}
+
+/// How devices can be started besides `POST /start/{id}`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Wake {
+    /// Allow `GET /start/{id}`, link previews and crawlers can start devices with it
+    pub get: bool,
+    /// Seconds wake tokens and pre-signed urls are valid
+    pub tokenttl: u64,
+    /// Key wake tokens are signed with, tokens don't survive a restart if not set
+    pub secret: Option<String>,
+}
+
+impl Default for Wake {
+    fn default() -> Self {
+        Self {
+            get: true,
+            tokenttl: 300,
+            secret: None,
+        }
+    }
+}
+
+/// Limits for start requests, unlimited if not set
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RateLimit {
+    /// Per client ip, clients on unix sockets aren't limited
+    pub client: Option<Limit>,
+    /// Per device, debounced starts don't count
+    pub device: Option<Limit>,
+}
+
+/// At most `requests` in `seconds`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Limit {
+    pub requests: u32,
+    pub seconds: u64,
+}
+
+/// Ports and frames the magic packet listener receives on
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Listen {
+    /// UDP ports, Wake-on-LAN uses 7 and 9
+    #[serde(default = "default_listen_ports")]
+    pub ports: Vec<u16>,
+    /// Also capture ethernet frames with ethertype 0x0842, needs `CAP_NET_RAW`
+    #[serde(default)]
+    pub raw: bool,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Self {
+            ports: default_listen_ports(),
+            raw: false,
+        }
+    }
+}
+
+/// Remote webol instance that sends magic packets into its own subnet
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Agent {
+    /// Referenced by `Device::via`
+    pub name: String,
+    pub url: String,
+    /// Sent as `Authorization` header
+    pub secret: Option<String>,
+    /// CA used to verify the agent, for self-signed certificates
+    pub ca: Option<String>,
+    /// Client certificate and key for agents using mutual TLS
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Auth {
     pub method: auth::Methods,
     pub secret: String,
 }
 
+/// Native TLS, enabled when the `[tls]` table is set
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tls {
+    pub cert: String,
+    pub key: String,
+    /// CA for client certificates, enables mutual TLS
+    pub clientca: Option<String>,
+    /// Address of a plain HTTP listener that redirects to HTTPS
+    pub redirect: Option<String>,
+}
+
+/// A single address the server listens on
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Listener {
+    /// `ip:port` or `unix:/path/to/socket`
+    pub addr: String,
+    /// Serve the `/admin` endpoints
+    #[serde(default)]
+    pub admin: bool,
+    /// Require auth, only disable on listeners that aren't reachable by untrusted clients
+    #[serde(default = "default_true")]
+    pub auth: bool,
+    /// Use TLS if `[tls]` is set, ignored for unix sockets
+    #[serde(default = "default_true")]
+    pub tls: bool,
+    /// Permissions of the unix socket, e.g. `0o660`
+    pub mode: Option<u32>,
+}
+
+/// Parsed [`Listener::addr`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Listener {
+    pub fn listen_addr(&self) -> ListenAddr {
+        self.addr.strip_prefix("unix:").map_or_else(
+            || ListenAddr::Tcp(self.addr.clone()),
+            |path| ListenAddr::Unix(PathBuf::from(path)),
+        )
+    }
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+const fn default_retention() -> u64 {
+    60
+}
+
+fn default_probes() -> Vec<Check> {
+    vec![Check::Icmp]
+}
+
+fn default_listen_ports() -> Vec<u16> {
+    vec![7, 9]
+}
+
 impl Config {
-    pub fn load() -> Result<Self, config::ConfigError> {
-        let config = config::Config::builder()
+    pub fn load() -> Result<Self, Error> {
+        let mut builder = config::Config::builder()
             .set_default("serveraddr", "0.0.0.0:7229")?
             .set_default("pingtimeout", 10)?
             .set_default("pingthreshold", 1)?
-            .set_default("timeoffset", 0)?
+            .set_default("pingretention", default_retention())?
+            .set_default("loglevel", "info")?
             .set_default("auth.method", "none")?
-            .set_default("auth.secret", "")?
-            .add_source(File::with_name("config.toml").required(false))
-            .add_source(File::with_name("config.dev.toml").required(false))
+            .set_default("auth.secret", "")?;
+
+        for file in FILES {
+            builder = builder.add_source(File::with_name(file).required(false));
+        }
+
+        let config: Self = builder
             .add_source(config::Environment::with_prefix("WEBOL").separator("_"))
-            .build()?;
+            .build()?
+            .try_deserialize()?;
+
+        let errors = config.validate();
+        if !errors.is_empty() {
+            return Err(Error::InvalidConfig { errors });
+        }
+
+        Ok(config)
+    }
+
+    /// The configured listeners, falls back to `serveraddr` with auth and admin endpoints
+    pub fn listeners(&self) -> Vec<Listener> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        vec![Listener {
+            addr: self.serveraddr.clone(),
+            admin: true,
+            auth: true,
+            tls: true,
+            mode: None,
+        }]
+    }
+
+    /// Checks the deserialized values, returns an error for every invalid field
+    pub fn validate(&self) -> Vec<FieldError> {
+        let mut errors = vec![];
+
+        match self
+            .serveraddr
+            .to_socket_addrs()
+            .map(|mut addrs| addrs.next())
+        {
+            Ok(Some(_)) => {}
+            Ok(None) => errors.push(FieldError::new(
+                "serveraddr",
+                "doesn't resolve to an address",
+            )),
+            Err(err) => errors.push(FieldError::new(
+                "serveraddr",
+                format!("\"{}\" is not a valid address: {err}", self.serveraddr),
+            )),
+        }
+
+        for (i, listener) in self.listeners.iter().enumerate() {
+            match listener.listen_addr() {
+                ListenAddr::Tcp(addr) => {
+                    if let Err(err) = addr.to_socket_addrs() {
+                        errors.push(FieldError::new(
+                            format!("listeners[{i}].addr"),
+                            format!("\"{addr}\" is not a valid address: {err}"),
+                        ));
+                    }
+                    if listener.mode.is_some() {
+                        errors.push(FieldError::new(
+                            format!("listeners[{i}].mode"),
+                            "only applies to unix sockets",
+                        ));
+                    }
+                }
+                ListenAddr::Unix(path) => {
+                    if path.as_os_str().is_empty() {
+                        errors.push(FieldError::new(
+                            format!("listeners[{i}].addr"),
+                            "unix socket path can't be empty",
+                        ));
+                    }
+                    if listener.mode.is_some_and(|mode| mode > 0o777) {
+                        errors.push(FieldError::new(
+                            format!("listeners[{i}].mode"),
+                            "has to be a permission mode like 0o660",
+                        ));
+                    }
+                }
+            }
+        }
+
+        if self.pingtimeout <= 0 {
+            errors.push(FieldError::new(
+                "pingtimeout",
+                format!(
+                    "has to be a positive number of minutes, got {}",
+                    self.pingtimeout
+                ),
+            ));
+        }
+
+        if self.pingprobes.is_empty() {
+            errors.push(FieldError::new("pingprobes", "needs at least one check"));
+        }
+        for (i, check) in self.pingprobes.iter().enumerate() {
+            if *check == Check::Tcp(0) {
+                errors.push(FieldError::new(
+                    format!("pingprobes[{i}]"),
+                    "0 is not a valid port",
+                ));
+            }
+            if self.pingprobes[..i].contains(check) {
+                errors.push(FieldError::new(
+                    format!("pingprobes[{i}]"),
+                    format!("\"{check}\" is listed twice"),
+                ));
+            }
+        }
+
+        if let Err(err) = EnvFilter::builder().parse(&self.loglevel) {
+            errors.push(FieldError::new(
+                "loglevel",
+                format!("\"{}\" is not a valid filter: {err}", self.loglevel),
+            ));
+        }
+
+        if matches!(self.auth.method, auth::Methods::Key) && self.auth.secret.is_empty() {
+            errors.push(FieldError::new(
+                "auth.secret",
+                "can't be empty when auth.method is \"key\"",
+            ));
+        }
+
+        let clientca = self.tls.as_ref().and_then(|tls| tls.clientca.as_ref());
+        if matches!(self.auth.method, auth::Methods::Mtls) && clientca.is_none() {
+            errors.push(FieldError::new(
+                "auth.method",
+                "\"mtls\" needs tls.clientca to be set",
+            ));
+        }
+
+        for (i, agent) in self.agents.iter().enumerate() {
+            if agent.name.is_empty() {
+                errors.push(FieldError::new(
+                    format!("agents[{i}].name"),
+                    "can't be empty",
+                ));
+            } else if self.agents[..i]
+                .iter()
+                .any(|other| other.name == agent.name)
+            {
+                errors.push(FieldError::new(
+                    format!("agents[{i}].name"),
+                    format!("\"{}\" is used by another agent", agent.name),
+                ));
+            }
+            if !(agent.url.starts_with("http://") || agent.url.starts_with("https://")) {
+                errors.push(FieldError::new(
+                    format!("agents[{i}].url"),
+                    format!("\"{}\" has to start with http:// or https://", agent.url),
+                ));
+            }
+            if agent.cert.is_some() != agent.key.is_some() {
+                errors.push(FieldError::new(
+                    format!("agents[{i}].cert"),
+                    "cert and key have to be set together",
+                ));
+            }
+            let files = [
+                ("ca", &agent.ca),
+                ("cert", &agent.cert),
+                ("key", &agent.key),
+            ];
+            for (field, path) in files {
+                if let Some(path) = path.as_ref().filter(|path| !Path::new(path).is_file()) {
+                    errors.push(FieldError::new(
+                        format!("agents[{i}].{field}"),
+                        format!("\"{path}\" is not a file"),
+                    ));
+                }
+            }
+        }
+
+        if let Some(listen) = &self.listen {
+            if listen.ports.is_empty() && !listen.raw {
+                errors.push(FieldError::new(
+                    "listen.ports",
+                    "can't be empty unless listen.raw is enabled",
+                ));
+            }
+            if listen.ports.contains(&0) {
+                errors.push(FieldError::new("listen.ports", "0 is not a valid port"));
+            }
+        }
+
+        let limits = [
+            ("ratelimit.client", self.ratelimit.client),
+            ("ratelimit.device", self.ratelimit.device),
+        ];
+        for (field, limit) in limits {
+            if limit.is_some_and(|limit| limit.requests == 0 || limit.seconds == 0) {
+                errors.push(FieldError::new(
+                    field,
+                    "requests and seconds have to be positive",
+                ));
+            }
+        }
+
+        if self.wake.tokenttl == 0 {
+            errors.push(FieldError::new(
+                "wake.tokenttl",
+                "has to be a positive number of seconds",
+            ));
+        }
+        if self.wake.secret.as_ref().is_some_and(String::is_empty) {
+            errors.push(FieldError::new(
+                "wake.secret",
+                "can't be empty, leave it out for a random one",
+            ));
+        }
+
+        if let Some(tls) = &self.tls {
+            let files = [
+                ("tls.cert", Some(&tls.cert)),
+                ("tls.key", Some(&tls.key)),
+                ("tls.clientca", tls.clientca.as_ref()),
+            ];
+            for (field, path) in files {
+                if let Some(path) = path.filter(|path| !Path::new(path).is_file()) {
+                    errors.push(FieldError::new(field, format!("\"{path}\" is not a file")));
+                }
+            }
+
+            if let Some(redirect) = &tls.redirect {
+                if let Err(err) = redirect.to_socket_addrs() {
+                    errors.push(FieldError::new(
+                        "tls.redirect",
+                        format!("\"{redirect}\" is not a valid address: {err}"),
+                    ));
+                }
+            }
+        }
+
+        errors
+    }
+
+    pub fn agent(&self, name: &str) -> Option<&Agent> {
+        self.agents.iter().find(|agent| agent.name == name)
+    }
 
-        config.try_deserialize()
+    /// Copy of the config that is safe to show, secrets are replaced
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if !config.auth.secret.is_empty() {
+            config.auth.secret = REDACTED.to_string();
+        }
+        if config.wake.secret.is_some() {
+            config.wake.secret = Some(REDACTED.to_string());
+        }
+        for agent in &mut config.agents {
+            if agent.secret.is_some() {
+                agent.secret = Some(REDACTED.to_string());
+            }
+        }
+        config
     }
 }
@@ -1,12 +1,17 @@
-use ipnetwork::IpNetworkError;
-use axum::http::StatusCode;
+use axum::extract::rejection::JsonRejection;
+use axum::http::{
+    header::{ALLOW, CONTENT_TYPE, RETRY_AFTER},
+    StatusCode,
+};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
+use ipnetwork::IpNetworkError;
 use mac_address::MacParseError;
+use serde::Serialize;
 use serde_json::json;
-use std::io;
+use std::{fmt::Display, io};
 use tracing::{error, warn};
-use utoipa::ToSchema;
+use utoipa::{ToResponse, ToSchema};
 
 #[derive(Debug, thiserror::Error, ToSchema)]
 pub enum Error {
@@ -40,8 +45,266 @@ pub enum Error {
         source: io::Error,
     },
 
+    #[error("blocking task: {source}")]
+    Blocking {
+        #[from]
+        source: tokio::task::JoinError,
+    },
+
     #[error("No ip set for device but ping requested")]
     NoIpOnPing,
+
+    #[error("arp check of pingprobes can't probe the IPv6 address of {id}")]
+    ArpIpv6 { id: String },
+
+    #[error("config: {source}")]
+    Config {
+        #[from]
+        source: config::ConfigError,
+    },
+
+    #[error("invalid config: {}", FieldError::join(errors))]
+    InvalidConfig { errors: Vec<FieldError> },
+
+    #[error("tls: {source}")]
+    Tls {
+        #[from]
+        source: tokio_rustls::rustls::Error,
+    },
+
+    #[error("no certificate or key found in \"{path}\"")]
+    NoPemItem { path: String },
+
+    #[error("relay: {source}")]
+    Relay {
+        #[from]
+        source: reqwest::Error,
+    },
+
+    #[error("agent {agent} answered with {status}")]
+    AgentResponse { agent: String, status: u16 },
+
+    #[error("unknown agent {agent}")]
+    UnknownAgent { agent: String },
+
+    #[error("relaying is disabled")]
+    RelayDisabled,
+
+    #[error("address parse: {source}")]
+    AddrParse {
+        #[from]
+        source: std::net::AddrParseError,
+    },
+
+    #[error("no broadcast address set and none can be derived from the ip")]
+    NoBroadcast,
+
+    #[error("unknown interface {interface}")]
+    UnknownInterface { interface: String },
+
+    #[error("ping: {source}")]
+    Ping {
+        #[from]
+        source: surge_ping::SurgeError,
+    },
+
+    #[error("discovery only supports IPv4 networks")]
+    Ipv6Discovery,
+
+    #[error("network {network} has more than {max} hosts")]
+    NetworkTooLarge { network: String, max: u32 },
+
+    #[error("device {id} already exists")]
+    DeviceExists { id: String },
+
+    #[error("packet listener is disabled")]
+    ListenDisabled,
+
+    #[error("invalid device id {id:?}: {reason}")]
+    InvalidId { id: String, reason: &'static str },
+
+    #[error("invalid fields: {}", FieldError::join(errors))]
+    Validation { errors: Vec<FieldError> },
+
+    #[error("payload: {source}")]
+    Payload {
+        #[from]
+        source: JsonRejection,
+    },
+
+    #[error("device {id} not found")]
+    DeviceNotFound { id: String },
+
+    #[error("device {id} is stored in version {version}, which is newer than this webol supports")]
+    DeviceVersion { id: String, version: u32 },
+
+    #[error("device {id} can't be upgraded from version {version}: {message}")]
+    Migration {
+        id: String,
+        version: u32,
+        message: String,
+    },
+
+    #[error("device {id} was changed since it was read")]
+    PreconditionFailed { id: String },
+
+    #[error("import: {message}")]
+    InvalidImport { message: String },
+
+    #[error("backup: {message}")]
+    InvalidBackup { message: String },
+
+    #[error("backup format version {version} is newer than this webol supports")]
+    UnsupportedBackup { version: u32 },
+
+    #[error("backup is encrypted but no passphrase was given")]
+    BackupPassphrase,
+
+    #[error("backup couldn't be decrypted, wrong passphrase or corrupted file")]
+    BackupDecrypt,
+
+    #[error("too many requests, retry after {retry_after}s")]
+    RateLimited { retry_after: u64 },
+
+    #[error("status websocket: {source}")]
+    StatusSocket {
+        #[from]
+        source: axum::Error,
+    },
+
+    #[error("expected the session uuid as text message")]
+    StatusMessage,
+
+    #[error("session results aren't sent anymore")]
+    StatusClosed,
+
+    #[error("ping session {uuid} not found")]
+    SessionNotFound { uuid: String },
+
+    #[error("wake token {reason}")]
+    InvalidToken { reason: &'static str },
+
+    #[error("starting devices with GET is disabled")]
+    GetStartDisabled,
+}
+
+/// Body of every error that isn't answered with [`Problem`] details
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    #[schema(example = "Requested device not found")]
+    pub error: &'static str,
+}
+
+// Dead Code allowed for the responses below because of use in OpenApi Macro (not really dead code)
+
+/// The request is malformed, invalid ids and payloads are answered with problem details
+#[allow(dead_code)]
+#[derive(ToResponse)]
+pub enum BadRequest {
+    Error(#[content("application/json")] ErrorBody),
+    Problem(#[content("application/problem+json")] Problem),
+}
+
+/// No or a wrong `Authorization` header or client certificate, only if authentication is enabled
+#[allow(dead_code)]
+#[derive(ToResponse)]
+pub struct Unauthorized;
+
+/// The device doesn't exist
+#[allow(dead_code)]
+#[derive(ToResponse)]
+pub struct NotFound(ErrorBody);
+
+/// A device with this id already exists
+#[allow(dead_code)]
+#[derive(ToResponse)]
+pub struct Conflict(ErrorBody);
+
+/// `If-Match` doesn't match the current `ETag`, the device was changed since it was read
+#[allow(dead_code)]
+#[derive(ToResponse)]
+pub struct PreconditionFailed(ErrorBody);
+
+/// The body isn't sent as `application/json`
+#[allow(dead_code)]
+#[derive(ToResponse)]
+#[response(content_type = "application/problem+json")]
+pub struct UnsupportedMediaType(Problem);
+
+/// Invalid fields, every one of them is listed in `errors`
+#[allow(dead_code)]
+#[derive(ToResponse)]
+#[response(content_type = "application/problem+json")]
+pub struct Unprocessable(Problem);
+
+/// Storage, config or network error on the server, the details are logged
+#[allow(dead_code)]
+#[derive(ToResponse)]
+#[response(example = json!({"error": "Server Error"}))]
+pub struct ServerError(ErrorBody);
+
+/// Problem details (RFC 9457), returned for requests the client has to fix
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl Problem {
+    fn response(
+        status: StatusCode,
+        title: &'static str,
+        detail: String,
+        errors: Vec<FieldError>,
+    ) -> Response {
+        let problem = Self {
+            kind: "about:blank",
+            title,
+            status: status.as_u16(),
+            detail,
+            errors,
+        };
+        (
+            status,
+            [(CONTENT_TYPE, "application/problem+json")],
+            json!(problem).to_string(),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    pub fn join(errors: &[Self]) -> String {
+        errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
 }
 
 impl IntoResponse for Error {
@@ -60,6 +323,10 @@ impl IntoResponse for Error {
                 error!("{source}");
                 (StatusCode::INTERNAL_SERVER_ERROR, "Server Error")
             }
+            Self::Blocking { source } => {
+                error!("blocking task failed: {source}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Server Error")
+            }
             Self::Io { source } => {
                 if source.kind() == io::ErrorKind::NotFound {
                     warn!("unknown device requested");
@@ -72,17 +339,24 @@ impl IntoResponse for Error {
             Self::MacParse { source } => {
                 warn!("{source}");
                 (
-                    StatusCode::INTERNAL_SERVER_ERROR,
+                    StatusCode::BAD_REQUEST,
                     "The given MAC-Address couldn't be parsed",
                 )
             }
             Self::IpParse { source } => {
                 warn!("{source}");
                 (
-                    StatusCode::INTERNAL_SERVER_ERROR,
+                    StatusCode::BAD_REQUEST,
                     "The given IP-Address couldn't be parsed",
                 )
             }
+            Self::ArpIpv6 { id } => {
+                warn!("Ping requested for IPv6 device {id} but pingprobes has arp");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "The arp check of pingprobes only works for IPv4, the device has an IPv6 address",
+                )
+            }
             Self::NoIpOnPing => {
                 warn!("Ping requested but no ip given");
                 (
@@ -90,10 +364,239 @@ impl IntoResponse for Error {
                     "No IP saved for device, ping can't be executed. Device may be started anyway",
                 )
             }
+            Self::Config { source } => {
+                error!("{source}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Config couldn't be loaded, previous config is kept",
+                )
+            }
+            Self::InvalidConfig { errors } => {
+                error!("invalid config: {}", FieldError::join(&errors));
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Config is invalid, previous config is kept",
+                )
+            }
+            Self::Tls { source } => {
+                error!("{source}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Server Error")
+            }
+            Self::NoPemItem { path } => {
+                error!("no certificate or key found in {path}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Server Error")
+            }
+            Self::Relay { source } => {
+                error!("{source}");
+                (StatusCode::BAD_GATEWAY, "Relay agent couldn't be reached")
+            }
+            Self::AgentResponse { agent, status } => {
+                error!("agent {agent} answered with {status}");
+                (
+                    StatusCode::BAD_GATEWAY,
+                    "Relay agent couldn't start the device",
+                )
+            }
+            Self::UnknownAgent { agent } => {
+                warn!("unknown agent {agent}");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "The given relay agent isn't configured",
+                )
+            }
+            Self::AddrParse { source } => {
+                warn!("{source}");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "The given broadcast address couldn't be parsed, expected ip:port",
+                )
+            }
+            Self::NoBroadcast => {
+                warn!("no broadcast address for device");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "No broadcast address set and the device ip has no prefix (e.g. 10.0.1.47/24) to derive it from",
+                )
+            }
+            Self::UnknownInterface { interface } => {
+                warn!("unknown interface {interface}");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "The given network interface doesn't exist",
+                )
+            }
+            Self::Ping { source } => {
+                error!("{source}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Server Error")
+            }
+            Self::Ipv6Discovery => {
+                warn!("ipv6 discovery requested");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Discovery only supports IPv4 networks",
+                )
+            }
+            Self::NetworkTooLarge { network, max } => {
+                warn!("network {network} has more than {max} hosts to probe");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "The network is too large to probe, use a smaller cidr",
+                )
+            }
+            Self::DeviceExists { id } => {
+                warn!("device {id} already exists");
+                (StatusCode::CONFLICT, "A device with this id already exists")
+            }
+            Self::RelayDisabled => {
+                warn!("relayed start requested but relaying is disabled");
+                (StatusCode::FORBIDDEN, "Relaying is disabled on this server")
+            }
+            Self::InvalidId { id, reason } => {
+                warn!("invalid device id {id:?}: {reason}");
+                return Problem::response(
+                    StatusCode::BAD_REQUEST,
+                    "Invalid device id",
+                    "Device ids may only contain letters, digits, '-', '_' and '.', can't start with '.' and are at most 64 characters long".to_string(),
+                    vec![FieldError::new("id", reason)],
+                );
+            }
+            Self::Validation { errors } => {
+                warn!("invalid fields: {}", FieldError::join(&errors));
+                return Problem::response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Invalid fields",
+                    FieldError::join(&errors),
+                    errors,
+                );
+            }
+            Self::Payload { source } => {
+                warn!("{source}");
+                return Problem::response(
+                    source.status(),
+                    "Invalid payload",
+                    source.body_text(),
+                    vec![],
+                );
+            }
+            Self::InvalidImport { message } => {
+                warn!("invalid import: {message}");
+                return Problem::response(
+                    StatusCode::BAD_REQUEST,
+                    "Invalid import",
+                    message,
+                    vec![],
+                );
+            }
+            Self::InvalidBackup { message } => {
+                warn!("invalid backup: {message}");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "The backup is invalid or corrupted",
+                )
+            }
+            Self::UnsupportedBackup { version } => {
+                warn!("backup format version {version} is unsupported");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "The backup was created by a newer webol version, upgrade to restore it",
+                )
+            }
+            Self::BackupPassphrase => {
+                warn!("encrypted backup without passphrase");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "The backup is encrypted, a passphrase is needed",
+                )
+            }
+            Self::BackupDecrypt => {
+                warn!("backup couldn't be decrypted");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "The backup couldn't be decrypted, check the passphrase",
+                )
+            }
+            Self::RateLimited { retry_after } => {
+                warn!("rate limited, retry after {retry_after}s");
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, retry_after.to_string())],
+                    Json(ErrorBody {
+                        error: "Too many requests, retry later",
+                    }),
+                )
+                    .into_response();
+            }
+            Self::StatusSocket { source } => {
+                warn!("{source}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Server Error")
+            }
+            Self::StatusMessage => {
+                warn!("status websocket got no uuid");
+                (
+                    StatusCode::BAD_REQUEST,
+                    "Expected the session uuid as text message",
+                )
+            }
+            Self::StatusClosed => {
+                error!("session results aren't sent anymore");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Server Error")
+            }
+            Self::SessionNotFound { uuid } => {
+                warn!("unknown ping session {uuid} requested");
+                (StatusCode::NOT_FOUND, "Requested session not found")
+            }
+            Self::InvalidToken { reason } => {
+                warn!("wake token {reason}");
+                (
+                    StatusCode::FORBIDDEN,
+                    "The wake token is invalid, expired or already used",
+                )
+            }
+            Self::GetStartDisabled => {
+                warn!("start with GET requested but it is disabled");
+                return (
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    [(ALLOW, "POST")],
+                    Json(ErrorBody {
+                        error: "Starting devices with GET is disabled, use POST",
+                    }),
+                )
+                    .into_response();
+            }
+            Self::DeviceNotFound { id } => {
+                warn!("unknown device {id} requested");
+                (StatusCode::NOT_FOUND, "Requested device not found")
+            }
+            Self::DeviceVersion { id, version } => {
+                error!("device {id} is stored in the unsupported version {version}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Server Error")
+            }
+            Self::Migration {
+                id,
+                version,
+                message,
+            } => {
+                error!("device {id} can't be upgraded from version {version}: {message}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Server Error")
+            }
+            Self::PreconditionFailed { id } => {
+                warn!("device {id} doesn't match If-Match");
+                (
+                    StatusCode::PRECONDITION_FAILED,
+                    "The device was changed since it was read, fetch it again",
+                )
+            }
+            Self::ListenDisabled => {
+                warn!("received packets requested but the listener is disabled");
+                (
+                    StatusCode::NOT_FOUND,
+                    "The packet listener is not enabled on this server",
+                )
+            }
         };
-        let body = Json(json!({
-            "error": error_message,
-        }));
+        let body = Json(ErrorBody {
+            error: error_message,
+        });
         (status, body).into_response()
     }
 }
@@ -0,0 +1,369 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info};
+use utoipa::ToSchema;
+
+use crate::error::{Error, FieldError};
+use crate::routes::device::{to_device, DPayload};
+use crate::storage::{Device, Metadata};
+use crate::AppState;
+
+/// Separates list items in CSV cells
+const CSV_LIST_SEPARATOR: char = ';';
+
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+    Yaml,
+}
+
+impl Format {
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next()?.trim();
+        match mime {
+            "application/json" => Some(Self::Json),
+            "text/csv" => Some(Self::Csv),
+            "application/yaml" | "application/x-yaml" | "text/yaml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Csv => "text/csv",
+            Self::Yaml => "application/yaml",
+        }
+    }
+
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+            Self::Yaml => "yaml",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Create and update the imported devices, keep all others
+    #[default]
+    Merge,
+    /// Also delete devices that aren't part of the import
+    Replace,
+}
+
+/// One imported device, the fields of `DPayload` plus its ping times
+#[derive(Deserialize)]
+struct Row {
+    #[serde(flatten)]
+    device: DPayload,
+    #[serde(default)]
+    times: Option<Vec<u64>>,
+}
+
+/// Flat representation for CSV, lists are joined with `;`
+#[derive(Serialize, Deserialize)]
+struct CsvRow {
+    id: String,
+    mac: String,
+    broadcast_addr: Option<String>,
+    ip: Option<String>,
+    via: Option<String>,
+    interface: Option<String>,
+    display_name: Option<String>,
+    description: Option<String>,
+    tags: Option<String>,
+    owner: Option<String>,
+    location: Option<String>,
+    icon: Option<String>,
+    times: Option<String>,
+}
+
+impl From<&Device> for CsvRow {
+    fn from(device: &Device) -> Self {
+        let meta = device.meta.clone();
+        Self {
+            id: device.id.to_string(),
+            mac: device.mac.to_string(),
+            broadcast_addr: device.broadcast_addr.map(|addr| addr.to_string()),
+            ip: device.ip.map(|ip| ip.to_string()),
+            via: device.via.clone(),
+            interface: device.interface.clone(),
+            display_name: meta.display_name,
+            description: meta.description,
+            tags: join(&meta.tags),
+            owner: meta.owner,
+            location: meta.location,
+            icon: meta.icon,
+            times: device.times.as_deref().and_then(join),
+        }
+    }
+}
+
+impl TryFrom<CsvRow> for Row {
+    type Error = String;
+
+    fn try_from(row: CsvRow) -> Result<Self, String> {
+        let times = row
+            .times
+            .map(|times| {
+                split(&times)
+                    .map(str::parse)
+                    .collect::<Result<Vec<u64>, _>>()
+                    .map_err(|_| format!("times: \"{times}\" is not a list of seconds"))
+            })
+            .transpose()?;
+        Ok(Self {
+            device: DPayload {
+                id: row.id,
+                mac: row.mac,
+                broadcast_addr: row.broadcast_addr,
+                ip: row.ip,
+                via: row.via,
+                interface: row.interface,
+                meta: Metadata {
+                    display_name: row.display_name,
+                    description: row.description,
+                    tags: row
+                        .tags
+                        .as_deref()
+                        .map(|tags| split(tags).map(String::from).collect())
+                        .unwrap_or_default(),
+                    owner: row.owner,
+                    location: row.location,
+                    icon: row.icon,
+                },
+            },
+            times,
+        })
+    }
+}
+
+fn join<T: ToString>(items: &[T]) -> Option<String> {
+    (!items.is_empty()).then(|| {
+        items
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(&CSV_LIST_SEPARATOR.to_string())
+    })
+}
+
+fn split(list: &str) -> impl Iterator<Item = &str> {
+    list.split(CSV_LIST_SEPARATOR)
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Serializes all devices, ordered by id so exports diff well
+pub fn export(mut devices: Vec<Device>, format: Format) -> Result<String, Error> {
+    devices.sort_by(|a, b| a.id.cmp(&b.id));
+
+    match format {
+        Format::Json => Ok(serde_json::to_string_pretty(&devices)?),
+        Format::Yaml => Ok(serde_yaml::to_string(&devices).map_err(io::Error::other)?),
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            for device in &devices {
+                writer
+                    .serialize(CsvRow::from(device))
+                    .map_err(io::Error::other)?;
+            }
+            let csv = writer.into_inner().map_err(io::Error::other)?;
+            Ok(String::from_utf8(csv).map_err(io::Error::other)?)
+        }
+    }
+}
+
+/// Rows of the import, rows that can't be read are kept as errors for the report
+fn parse(body: &str, format: Format) -> Result<Vec<Result<Row, String>>, Error> {
+    let invalid = |err: &dyn std::fmt::Display| Error::InvalidImport {
+        message: err.to_string(),
+    };
+    let values = match format {
+        Format::Json => serde_json::from_str::<Vec<Value>>(body).map_err(|err| invalid(&err))?,
+        Format::Yaml => serde_yaml::from_str::<Vec<Value>>(body).map_err(|err| invalid(&err))?,
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(body.as_bytes());
+            return Ok(reader
+                .deserialize::<CsvRow>()
+                .map(|row| row.map_err(|err| err.to_string()).and_then(Row::try_from))
+                .collect());
+        }
+    };
+
+    Ok(values
+        .into_iter()
+        .map(|value| serde_json::from_value(value).map_err(|err| err.to_string()))
+        .collect())
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Create,
+    Update,
+    Unchanged,
+    Delete,
+    Invalid,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct RowReport {
+    /// Position in the import starting at 1, not set for deleted devices
+    row: Option<usize>,
+    id: Option<String>,
+    action: Action,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Report {
+    /// `false` for dry runs and imports with invalid rows, nothing was changed then
+    pub applied: bool,
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub deleted: usize,
+    pub invalid: usize,
+    pub rows: Vec<RowReport>,
+}
+
+impl Report {
+    fn count(&self, action: Action) -> usize {
+        self.rows.iter().filter(|row| row.action == action).count()
+    }
+}
+
+/// Validates every row with the same rules as `PUT /device` and applies the import if all
+/// rows are valid and it's no dry run
+pub fn import(
+    state: &AppState,
+    body: &str,
+    format: Format,
+    mode: Mode,
+    dry_run: bool,
+) -> Result<Report, Error> {
+    let rows = parse(body, format)?;
+    info!(rows = rows.len(), ?format, ?mode, dry_run, "import devices");
+
+    let _lock = Device::lock();
+    let mut existing = Device::read_all()?
+        .into_iter()
+        .map(|device| (device.id.clone(), device))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut reports = vec![];
+    let mut changes = vec![];
+    let mut seen = HashSet::new();
+    for (i, row) in rows.into_iter().enumerate() {
+        let mut report = RowReport {
+            row: Some(i + 1),
+            id: None,
+            action: Action::Invalid,
+            errors: vec![],
+        };
+
+        let row = match row {
+            Ok(row) => row,
+            Err(err) => {
+                report.errors.push(FieldError::new("row", err));
+                reports.push(report);
+                continue;
+            }
+        };
+        report.id = Some(row.device.id.clone());
+
+        let device = match to_device(state, row.device, None) {
+            Ok(device) => device,
+            Err(Error::Validation { errors }) => {
+                report.errors = errors;
+                reports.push(report);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if !seen.insert(device.id.clone()) {
+            report
+                .errors
+                .push(FieldError::new("id", "appears more than once"));
+            reports.push(report);
+            continue;
+        }
+
+        let current = existing.get(&device.id);
+        let device = Device {
+            times: row.times.or_else(|| current.and_then(|c| c.times.clone())),
+            ..device
+        };
+        report.action = match current {
+            None => Action::Create,
+            Some(current) if json!(current) == json!(device) => Action::Unchanged,
+            Some(_) => Action::Update,
+        };
+        if report.action != Action::Unchanged {
+            changes.push(device);
+        }
+        reports.push(report);
+    }
+
+    let mut deletions = vec![];
+    if mode == Mode::Replace {
+        existing.retain(|id, _| !seen.contains(id));
+        for id in existing.into_keys() {
+            reports.push(RowReport {
+                row: None,
+                id: Some(id.to_string()),
+                action: Action::Delete,
+                errors: vec![],
+            });
+            deletions.push(id);
+        }
+    }
+
+    let mut report = Report {
+        applied: false,
+        created: 0,
+        updated: 0,
+        unchanged: 0,
+        deleted: 0,
+        invalid: 0,
+        rows: reports,
+    };
+    report.created = report.count(Action::Create);
+    report.updated = report.count(Action::Update);
+    report.unchanged = report.count(Action::Unchanged);
+    report.deleted = report.count(Action::Delete);
+    report.invalid = report.count(Action::Invalid);
+
+    if dry_run || report.invalid > 0 {
+        debug!(invalid = report.invalid, "import not applied");
+        return Ok(report);
+    }
+
+    for device in changes {
+        device.write()?;
+    }
+    for id in deletions {
+        Device::delete(&id)?;
+    }
+    report.applied = true;
+    info!(
+        created = report.created,
+        updated = report.updated,
+        deleted = report.deleted,
+        "import applied"
+    );
+
+    Ok(report)
+}
@@ -0,0 +1,261 @@
+//! Wake-on-LAN server with an HTTP API, see the README for usage.
+//!
+//! The library part is meant for other tools:
+//! - [`wol`] creates and sends magic packets
+//! - [`storage`] reads and writes the device files of a webol server
+//! - [`client`] talks to a running server
+//!
+//! The remaining modules make up the server run by the `webol` binary. They are public for it and
+//! the integration tests, but aren't part of the stable API.
+
+use crate::{
+    agent::Agents,
+    config::Config,
+    routes::{admin, device, devices, discover, packets, relay, sessions, start, status, wake},
+    services::{
+        listen::Packets,
+        ping::{Network, Probe},
+        ratelimit::RateLimiter,
+        reload::LogHandle,
+        sessions::Sessions,
+    },
+    tls::Tls,
+    token::Tokens,
+    wol::{PacketSender, Udp},
+};
+use arc_swap::ArcSwap;
+use axum::{
+    middleware::from_fn_with_state,
+    routing::{get, post},
+    Router,
+};
+use std::sync::Arc;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+pub mod client;
+pub mod error;
+pub mod storage;
+pub mod wol;
+
+mod hex;
+
+#[doc(hidden)]
+pub mod agent;
+#[doc(hidden)]
+pub mod auth;
+#[doc(hidden)]
+pub mod backup;
+#[doc(hidden)]
+pub mod cli;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod inventory;
+#[doc(hidden)]
+pub mod routes;
+#[doc(hidden)]
+pub mod server;
+#[doc(hidden)]
+pub mod services;
+#[doc(hidden)]
+pub mod tls;
+#[doc(hidden)]
+pub mod token;
+
+pub use client::Client;
+pub use error::Error;
+pub use storage::{Device, DeviceId};
+pub use wol::{create_buffer, magic_packet, send_to};
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        start::post,
+        start::get,
+        wake::token,
+        wake::page,
+        wake::post,
+        device::get,
+        device::post,
+        device::put,
+        device::update,
+        device::patch,
+        device::rename,
+        devices::get,
+        devices::export,
+        devices::import,
+        status::status,
+        sessions::list,
+        sessions::get,
+        sessions::delete,
+        relay::post,
+        discover::discover,
+        discover::import,
+        packets::get,
+        admin::reload,
+        admin::config,
+        admin::backup,
+        admin::restore,
+    ),
+    components(
+        schemas(
+            start::SPayload,
+            start::Response,
+            sessions::Session,
+            services::ping::SessionState,
+            wake::TokenResponse,
+            device::DPayload,
+            device::RenamePayload,
+            storage::Device,
+            storage::Metadata,
+            error::Problem,
+            error::FieldError,
+            devices::Sort,
+            devices::Order,
+            inventory::Format,
+            inventory::Mode,
+            inventory::Action,
+            inventory::Report,
+            inventory::RowReport,
+            backup::Restored,
+            crate::agent::RelayPayload,
+            crate::agent::RelayResponse,
+            discover::DiscoverPayload,
+            discover::ImportResponse,
+            discover::ImportError,
+            services::discover::Candidate,
+            services::listen::Packet,
+            error::ErrorBody,
+        ),
+        responses(
+            error::BadRequest,
+            error::Unauthorized,
+            error::NotFound,
+            error::Conflict,
+            error::PreconditionFailed,
+            error::UnsupportedMediaType,
+            error::Unprocessable,
+            error::ServerError,
+        )
+    ),
+    modifiers(&SecurityAddon),
+    tags(
+        (name = "Webol", description = "Webol API")
+    )
+)]
+pub struct ApiDoc;
+
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "api_key",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("Authorization"))),
+            );
+        }
+    }
+}
+
+/// Routes of one listener, the `/admin` endpoints are only added with `admin` and auth is only
+/// checked with `auth`
+pub fn router(state: &Arc<AppState>, admin: bool, auth: bool) -> Router {
+    let mut app = Router::new()
+        .route("/start/:id", post(start::post).get(start::get))
+        .route("/start/:id/token", post(wake::token))
+        .route("/device", post(device::post).put(device::put))
+        .route(
+            "/device/:id",
+            get(device::get).put(device::update).patch(device::patch),
+        )
+        .route("/device/:id/rename", post(device::rename))
+        .route("/devices", get(devices::get))
+        .route("/devices/export", get(devices::export))
+        .route("/devices/import", post(devices::import))
+        .route("/status", get(status::status))
+        .route("/sessions", get(sessions::list))
+        .route(
+            "/sessions/:uuid",
+            get(sessions::get).delete(sessions::delete),
+        )
+        .route("/relay", post(relay::post))
+        .route("/discover", post(discover::discover))
+        .route("/discover/import", post(discover::import))
+        .route("/packets", get(packets::get));
+
+    if admin {
+        app = app
+            .route("/admin/reload", post(admin::reload))
+            .route("/admin/config", get(admin::config))
+            .route("/admin/backup", post(admin::backup))
+            .route("/admin/restore", post(admin::restore));
+    }
+
+    if auth {
+        app = app.route_layer(from_fn_with_state(state.clone(), auth::auth));
+    }
+    // The token authenticates the request
+    app = app.route("/wake/:token", get(wake::page).post(wake::post));
+
+    app.merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()))
+        .with_state(state.clone())
+}
+
+pub struct AppState {
+    config: ArcSwap<Config>,
+    log_handle: LogHandle,
+    tls: Option<Tls>,
+    sessions: Sessions,
+    packets: Option<Arc<Packets>>,
+    wol: Arc<dyn PacketSender>,
+    probe: Arc<dyn Probe>,
+    agents: Agents,
+    limits: RateLimiter,
+    tokens: Tokens,
+}
+
+impl AppState {
+    /// State without TLS and packet listener, sending magic packets over UDP and pinging devices
+    pub fn new(config: Config, log_handle: LogHandle) -> Self {
+        Self {
+            agents: Agents::load(&config.agents),
+            config: ArcSwap::from_pointee(config),
+            log_handle,
+            tls: None,
+            sessions: Sessions::default(),
+            packets: None,
+            wol: Arc::new(Udp),
+            probe: Arc::new(Network),
+            limits: RateLimiter::default(),
+            tokens: Tokens::default(),
+        }
+    }
+
+    pub fn with_tls(self, tls: Option<Tls>) -> Self {
+        Self { tls, ..self }
+    }
+
+    /// Magic packets received by the listener, see [`services::listen`]
+    pub fn with_packets(self, packets: Option<Arc<Packets>>) -> Self {
+        Self { packets, ..self }
+    }
+
+    /// Replaces how magic packets are sent, e.g. to record them in tests
+    pub fn with_sender(self, wol: Arc<dyn PacketSender>) -> Self {
+        Self { wol, ..self }
+    }
+
+    /// Replaces how devices are checked for being online after a start with ping
+    pub fn with_probe(self, probe: Arc<dyn Probe>) -> Self {
+        Self { probe, ..self }
+    }
+
+    pub fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+}
@@ -1,75 +1,45 @@
-use crate::{
-    config::Config,
-    routes::{device, devices, start, status},
-    services::ping::{BroadcastCommand, StatusMap},
+use clap::Parser;
+use std::{net::ToSocketAddrs, sync::Arc};
+use tokio::task::JoinSet;
+use tracing::{error, info, trace};
+use tracing_subscriber::{fmt, prelude::*};
+use webol::{
+    cli::{self, Cli, Commands},
+    config::{Config, ListenAddr},
+    router, server,
+    services::{
+        listen::{self, Packets},
+        reload,
+    },
     storage::Device,
+    tls::Tls,
+    AppState,
 };
-use axum::{
-    middleware::from_fn_with_state,
-    routing::{get, post},
-    Router,
-};
-use dashmap::DashMap;
-use std::{env, sync::Arc};
-use tokio::sync::broadcast::{channel, Sender};
-use tracing::{info, level_filters::LevelFilter, trace};
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
-use utoipa::{
-    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
-    Modify, OpenApi,
-};
-use utoipa_swagger_ui::SwaggerUi;
-
-mod auth;
-mod config;
-mod error;
-mod routes;
-mod services;
-mod storage;
-mod wol;
-
-#[derive(OpenApi)]
-#[openapi(
-    paths(
-        start::post,
-        start::get,
-        device::get,
-        device::post,
-        device::put,
-        devices::get,
-    ),
-    components(
-        schemas(
-            start::SPayload,
-            start::Response,
-            device::DPayload,
-            storage::DeviceSchema,
-        )
-    ),
-    modifiers(&SecurityAddon),
-    tags(
-        (name = "Webol", description = "Webol API")
-    )
-)]
-struct ApiDoc;
-
-struct SecurityAddon;
-
-impl Modify for SecurityAddon {
-    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
-        if let Some(components) = openapi.components.as_mut() {
-            components.add_security_scheme(
-                "api_key",
-                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("Authorization"))),
-            );
-        }
-    }
-}
 
 #[tokio::main]
 async fn main() -> color_eyre::eyre::Result<()> {
     color_eyre::install()?;
 
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Commands::CheckConfig) => {
+            if !cli::check_config()? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Commands::Listen { port, raw }) => return cli::listen(port, raw).await,
+        Some(Commands::Backup { output, passphrase }) => {
+            return cli::backup(output, passphrase.as_deref())
+        }
+        Some(Commands::Restore {
+            file,
+            passphrase,
+            no_config,
+        }) => return cli::restore(&file, passphrase.as_deref(), !no_config),
+        None => {}
+    }
+
     let config = Config::load()?;
 
     let writer_time =
@@ -79,7 +49,11 @@ async fn main() -> color_eyre::eyre::Result<()> {
     let file_appender = tracing_appender::rolling::daily("logs", "webol.log");
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
 
+    let (log_filter, log_handle) =
+        tracing_subscriber::reload::Layer::new(reload::log_filter(&config.loglevel));
+
     tracing_subscriber::registry()
+        .with(log_filter)
         .with(
             fmt::layer()
                 .with_timer(writer_time)
@@ -87,11 +61,6 @@ async fn main() -> color_eyre::eyre::Result<()> {
                 .with_ansi(false),
         )
         .with(fmt::layer().with_timer(time))
-        .with(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
         .init();
     trace!("logging initialized");
 
@@ -100,37 +69,52 @@ async fn main() -> color_eyre::eyre::Result<()> {
     let version = env!("CARGO_PKG_VERSION");
     info!(?version, "start webol");
 
-    let (tx, _) = channel(32);
-
-    let ping_map: StatusMap = DashMap::new();
-
-    let shared_state = AppState {
-        config: config.clone(),
-        ping_send: tx,
-        ping_map,
-    };
+    let listeners = config.listeners();
+    let tls = config.tls.as_ref().map(Tls::load).transpose()?;
+    let redirect = config.tls.as_ref().and_then(|tls| tls.redirect.clone());
+    let packets = config.listen.clone().map(|listen| {
+        let packets = Arc::new(Packets::default());
+        listen::spawn(listen, packets.clone());
+        packets
+    });
+
+    let shared_state = Arc::new(
+        AppState::new(config, log_handle)
+            .with_tls(tls)
+            .with_packets(packets),
+    );
+
+    if let Err(err) = reload::spawn(shared_state.clone()) {
+        error!("couldn't watch config and device files, hot-reload disabled: {err}");
+    }
 
-    let app = Router::new()
-        .route("/start/:id", post(start::post).get(start::get))
-        .route("/device", post(device::post).put(device::put))
-        .route("/device/:id", get(device::get))
-        .route("/devices", get(devices::get))
-        .route("/status", get(status::status))
-        .route_layer(from_fn_with_state(shared_state.clone(), auth::auth))
-        .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()))
-        .with_state(Arc::new(shared_state));
+    if let (Some(redirect), Some(_)) = (redirect, shared_state.tls()) {
+        // Redirect to the first listener that serves https
+        let port = listeners
+            .iter()
+            .filter(|listener| listener.tls)
+            .find_map(|listener| match listener.listen_addr() {
+                ListenAddr::Tcp(addr) => addr.to_socket_addrs().ok()?.next(),
+                ListenAddr::Unix(_) => None,
+            })
+            .map(|addr| addr.port());
+        if let Some(port) = port {
+            tokio::spawn(async move {
+                if let Err(err) = server::redirect(redirect, port).await {
+                    error!("https redirect stopped: {err}");
+                }
+            });
+        }
+    }
 
-    let addr = config.serveraddr;
-    info!("start server on {}", addr);
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    let mut servers = JoinSet::new();
+    for listener in listeners {
+        let app = router(&shared_state, listener.admin, listener.auth);
+        servers.spawn(server::serve(listener, app, shared_state.clone()));
+    }
+    while let Some(server) = servers.join_next().await {
+        server??;
+    }
 
     Ok(())
 }
-
-#[derive(Clone)]
-pub struct AppState {
-    config: Config,
-    ping_send: Sender<BroadcastCommand>,
-    ping_map: StatusMap,
-}
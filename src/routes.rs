@@ -1,4 +1,10 @@
-pub mod start;
+pub mod admin;
 pub mod device;
 pub mod devices;
+pub mod discover;
+pub mod packets;
+pub mod relay;
+pub mod sessions;
+pub mod start;
 pub mod status;
+pub mod wake;
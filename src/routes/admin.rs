@@ -0,0 +1,136 @@
+use crate::backup;
+use crate::error::Error;
+use crate::services::reload;
+use crate::AppState;
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::{info, warn};
+use utoipa::IntoParams;
+
+/// Header the backup passphrase is sent in, so it doesn't end up in access logs
+const PASSPHRASE: &str = "x-backup-passphrase";
+
+#[utoipa::path(
+    post,
+    path = "/admin/reload",
+    responses(
+        (status = 200, description = "reload the config from disk", body = Object, example = json!({"reloaded": true})),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 500, description = "the config couldn't be read or is invalid, the previous config is kept", body = ErrorBody)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn reload(State(state): State<Arc<AppState>>) -> Result<Json<Value>, Error> {
+    info!("reload requested");
+
+    reload::reload(&state)?;
+
+    Ok(Json(json!({ "reloaded": true })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/config",
+    responses(
+        (status = 200, description = "effective config with secrets redacted", body = Object),
+        (status = 401, response = crate::error::Unauthorized)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn config(State(state): State<Arc<AppState>>) -> Json<Value> {
+    info!("get config");
+
+    Json(json!(state.config.load().redacted()))
+}
+
+fn passphrase(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(PASSPHRASE)
+        .and_then(|passphrase| passphrase.to_str().ok())
+        .filter(|passphrase| !passphrase.is_empty())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/backup",
+    params(
+        ("x-backup-passphrase" = Option<String>, Header, description = "encrypt the archive with this passphrase")
+    ),
+    responses(
+        (status = 200, description = "archive with all devices, their boot history and the config files", content_type = "application/octet-stream"),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn backup(headers: HeaderMap) -> Result<(HeaderMap, Bytes), Error> {
+    info!("backup requested");
+
+    // Deriving the key and reading the device files block
+    let passphrase = passphrase(&headers).map(String::from);
+    let archive =
+        tokio::task::spawn_blocking(move || backup::create(passphrase.as_deref())).await??;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_static("attachment; filename=\"webol-backup.webol\""),
+    );
+    Ok((headers, Bytes::from(archive)))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct RestoreQuery {
+    /// Also restore the config files, defaults to true
+    config: Option<bool>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/restore",
+    params(
+        RestoreQuery,
+        ("x-backup-passphrase" = Option<String>, Header, description = "passphrase the archive was encrypted with")
+    ),
+    request_body(content = Vec<u8>, description = "archive from `/admin/backup` or `webol backup`", content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "replace all devices and the config with the archive's", body = Restored),
+        (status = 400, description = "invalid archive, newer format, missing or wrong passphrase", body = ErrorBody),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn restore(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<RestoreQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, Error> {
+    info!("restore requested");
+
+    let passphrase = passphrase(&headers).map(String::from);
+    let restored = tokio::task::spawn_blocking(move || {
+        let archive = backup::open(&body, passphrase.as_deref())?;
+        backup::restore(archive, query.config.unwrap_or(true))
+    })
+    .await??;
+
+    if !restored.config.is_empty() {
+        if let Err(err) = reload::reload(&state) {
+            warn!("restored config couldn't be applied: {err}");
+        }
+    }
+
+    Ok(Json(json!(restored)))
+}
@@ -1,12 +1,19 @@
-use crate::error::Error;
-use crate::storage::Device;
-use axum::extract::Path;
+use crate::error::{Error, FieldError};
+use crate::storage::{Device, DeviceId, Metadata};
+use crate::wol::interface_index;
+use crate::AppState;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Path, State};
+use axum::http::header::{ETAG, IF_MATCH, LOCATION};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::Json;
 use ipnetwork::IpNetwork;
 use mac_address::MacAddress;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::Arc;
 use tracing::{debug, info};
 use utoipa::ToSchema;
 
@@ -14,29 +21,44 @@ use utoipa::ToSchema;
     get,
     path = "/device/{id}",
     responses(
-        (status = 200, description = "Get `Device` information", body = [Device])
+        (status = 200, description = "Get `Device` information", body = Device,
+            headers(("etag" = String, description = "Current version of the device, for `If-Match`"))),
+        (status = 400, description = "invalid id", content(("application/json" = ErrorBody), ("application/problem+json" = Problem))),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, response = crate::error::NotFound),
+        (status = 500, response = crate::error::ServerError)
     ),
     params(
         ("id" = String, Path, description = "device id")
     ),
     security((), ("api_key" = []))
 )]
-pub async fn get(Path(id): Path<String>) -> Result<Json<Value>, Error> {
+pub async fn get(Path(id): Path<String>) -> Result<(HeaderMap, Json<Value>), Error> {
     info!("get device from path {}", id);
 
-    let device = Device::read(&id)?;
+    let device = Device::read(&DeviceId::new(id)?)?;
 
     debug!("got device {:?}", device);
 
-    Ok(Json(json!(device)))
+    Ok((etag(&device), Json(json!(device))))
 }
 
-#[derive(Deserialize, ToSchema)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct DPayload {
-    id: String,
-    mac: String,
-    broadcast_addr: String,
-    ip: Option<String>,
+    /// Can be left out when updating, the id in the path is used
+    #[serde(default)]
+    #[schema(pattern = "^[A-Za-z0-9_-][A-Za-z0-9._-]{0,63}$")]
+    pub id: String,
+    pub mac: String,
+    /// `ip:port`, derived from `ip` if it has a prefix (e.g. `10.0.1.47/24`)
+    pub broadcast_addr: Option<String>,
+    pub ip: Option<String>,
+    /// Name of a configured relay agent
+    pub via: Option<String>,
+    /// Interface IPv6 packets are sent on
+    pub interface: Option<String>,
+    #[serde(flatten)]
+    pub meta: Metadata,
 }
 
 #[utoipa::path(
@@ -44,32 +66,44 @@ pub struct DPayload {
     path = "/device",
     request_body = DPayload,
     responses(
-        (status = 200, description = "add device to storage", body = [Device])
+        (status = 201, description = "add device to storage", body = Device,
+            headers(("location" = String), ("etag" = String))),
+        (status = 400, description = "malformed json", content(("application/json" = ErrorBody), ("application/problem+json" = Problem))),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 409, response = crate::error::Conflict),
+        (status = 415, response = crate::error::UnsupportedMediaType),
+        (status = 422, response = crate::error::Unprocessable),
+        (status = 500, response = crate::error::ServerError)
     ),
     security((), ("api_key" = []))
 )]
-pub async fn put(Json(payload): Json<DPayload>) -> Result<Json<Value>, Error> {
+pub async fn put(
+    State(state): State<Arc<AppState>>,
+    payload: Result<Json<DPayload>, JsonRejection>,
+) -> Result<(StatusCode, HeaderMap, Json<Value>), Error> {
+    let Json(payload) = payload?;
     info!(
-        "add device {} ({}, {}, {:?})",
+        "add device {} ({}, {:?}, {:?})",
         payload.id, payload.mac, payload.broadcast_addr, payload.ip
     );
 
-    let ip = if let Some(ip_s) = payload.ip {
-        Some(IpNetwork::from_str(&ip_s)?)
-    } else {
-        None
-    };
-    let mac = MacAddress::from_str(&payload.mac)?;
-    let device = Device {
-        id: payload.id,
-        mac,
-        broadcast_addr: payload.broadcast_addr,
-        ip,
-        times: None,
-    };
-    device.write()?;
+    let device = to_device(&state, payload, None)?;
+    {
+        let _lock = Device::lock();
+        if Device::exists(&device.id) {
+            return Err(Error::DeviceExists {
+                id: device.id.to_string(),
+            });
+        }
+        device.write()?;
+    }
 
-    Ok(Json(json!(device)))
+    let mut headers = etag(&device);
+    if let Ok(location) = HeaderValue::from_str(&format!("/device/{}", device.id)) {
+        headers.insert(LOCATION, location);
+    }
+
+    Ok((StatusCode::CREATED, headers, Json(json!(device))))
 }
 
 #[utoipa::path(
@@ -77,32 +111,331 @@ pub async fn put(Json(payload): Json<DPayload>) -> Result<Json<Value>, Error> {
     path = "/device",
     request_body = DPayload,
     responses(
-        (status = 200, description = "update device in storage", body = [Device])
+        (status = 200, description = "update device in storage, use `PUT /device/{id}` instead", body = Device),
+        (status = 400, description = "invalid id or malformed json", content(("application/json" = ErrorBody), ("application/problem+json" = Problem))),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, response = crate::error::NotFound),
+        (status = 412, response = crate::error::PreconditionFailed),
+        (status = 415, response = crate::error::UnsupportedMediaType),
+        (status = 422, response = crate::error::Unprocessable),
+        (status = 500, response = crate::error::ServerError)
     ),
-    security((), ("api_key" = []))
+    security((), ("api_key" = [])),
 )]
-pub async fn post(Json(payload): Json<DPayload>) -> Result<Json<Value>, Error> {
+pub async fn post(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    payload: Result<Json<DPayload>, JsonRejection>,
+) -> Result<(HeaderMap, Json<Value>), Error> {
+    let Json(payload) = payload?;
     info!(
-        "edit device {} ({}, {}, {:?})",
+        "edit device {} ({}, {:?}, {:?})",
         payload.id, payload.mac, payload.broadcast_addr, payload.ip
     );
 
-    let ip = if let Some(ip_s) = payload.ip {
-        Some(IpNetwork::from_str(&ip_s)?)
-    } else {
-        None
+    let id = DeviceId::new(payload.id.as_str())?;
+    update_device(&state, &id, &headers, payload)
+}
+
+#[utoipa::path(
+    put,
+    path = "/device/{id}",
+    request_body = DPayload,
+    responses(
+        (status = 200, description = "replace the device, its ping times are kept", body = Device,
+            headers(("etag" = String))),
+        (status = 400, description = "invalid id or malformed json", content(("application/json" = ErrorBody), ("application/problem+json" = Problem))),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, response = crate::error::NotFound),
+        (status = 412, response = crate::error::PreconditionFailed),
+        (status = 415, response = crate::error::UnsupportedMediaType),
+        (status = 422, response = crate::error::Unprocessable),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    params(
+        ("id" = String, Path, description = "device id"),
+        ("if-match" = Option<String>, Header, description = "`ETag` the device was read with")
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn update(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    payload: Result<Json<DPayload>, JsonRejection>,
+) -> Result<(HeaderMap, Json<Value>), Error> {
+    let Json(payload) = payload?;
+    info!("update device {id}");
+
+    update_device(&state, &DeviceId::new(id)?, &headers, payload)
+}
+
+#[utoipa::path(
+    patch,
+    path = "/device/{id}",
+    request_body(content = Value, description = "JSON merge patch (RFC 7396) of `DPayload`, `null` removes a field", content_type = "application/merge-patch+json"),
+    responses(
+        (status = 200, description = "update single fields of the device", body = Device,
+            headers(("etag" = String))),
+        (status = 400, description = "invalid id or malformed json", content(("application/json" = ErrorBody), ("application/problem+json" = Problem))),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, response = crate::error::NotFound),
+        (status = 412, response = crate::error::PreconditionFailed),
+        (status = 415, response = crate::error::UnsupportedMediaType),
+        (status = 422, response = crate::error::Unprocessable),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    params(
+        ("id" = String, Path, description = "device id"),
+        ("if-match" = Option<String>, Header, description = "`ETag` the device was read with")
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn patch(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    patch: Result<Json<Value>, JsonRejection>,
+) -> Result<(HeaderMap, Json<Value>), Error> {
+    let Json(patch) = patch?;
+    info!("patch device {id}");
+    let id = DeviceId::new(id)?;
+
+    let _lock = Device::lock();
+    let current = read_matching(&id, &headers)?;
+
+    let mut merged = json!(current);
+    merge_patch(&mut merged, patch);
+    let payload = serde_json::from_value::<DPayload>(merged).map_err(|err| Error::Validation {
+        errors: vec![FieldError::new("body", err.to_string())],
+    })?;
+
+    write_update(&state, current, payload)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RenamePayload {
+    /// New id of the device
+    #[schema(pattern = "^[A-Za-z0-9_-][A-Za-z0-9._-]{0,63}$")]
+    id: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/device/{id}/rename",
+    request_body = RenamePayload,
+    responses(
+        (status = 200, description = "move the device to a new id, its ping times and running ping sessions are kept", body = Device,
+            headers(("location" = String), ("etag" = String))),
+        (status = 400, description = "invalid id or malformed json", content(("application/json" = ErrorBody), ("application/problem+json" = Problem))),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, response = crate::error::NotFound),
+        (status = 409, description = "a device with the new id already exists", body = ErrorBody),
+        (status = 412, response = crate::error::PreconditionFailed),
+        (status = 415, response = crate::error::UnsupportedMediaType),
+        (status = 422, description = "the body has no valid `id`", body = Problem, content_type = "application/problem+json"),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    params(
+        ("id" = String, Path, description = "current device id"),
+        ("if-match" = Option<String>, Header, description = "`ETag` the device was read with")
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn rename(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    payload: Result<Json<RenamePayload>, JsonRejection>,
+) -> Result<(HeaderMap, Json<Value>), Error> {
+    let Json(payload) = payload?;
+    info!("rename device {id} to {}", payload.id);
+    let id = DeviceId::new(id)?;
+    let new_id = DeviceId::new(payload.id)?;
+
+    let device = {
+        let _lock = Device::lock();
+        read_matching(&id, &headers)?;
+        Device::rename(&id, new_id)?
+    };
+
+    state.sessions.rename(&id, &device.id);
+
+    let mut headers = etag(&device);
+    if let Ok(location) = HeaderValue::from_str(&format!("/device/{}", device.id)) {
+        headers.insert(LOCATION, location);
+    }
+
+    Ok((headers, Json(json!(device))))
+}
+
+fn update_device(
+    state: &AppState,
+    id: &DeviceId,
+    headers: &HeaderMap,
+    payload: DPayload,
+) -> Result<(HeaderMap, Json<Value>), Error> {
+    let _lock = Device::lock();
+    let current = read_matching(id, headers)?;
+    write_update(state, current, payload)
+}
+
+/// Writes `payload` over `current`, keeping its ping times. The id can't be changed.
+fn write_update(
+    state: &AppState,
+    current: Device,
+    mut payload: DPayload,
+) -> Result<(HeaderMap, Json<Value>), Error> {
+    if payload.id.is_empty() {
+        payload.id = current.id.to_string();
+    } else if payload.id != current.id.as_str() {
+        return Err(Error::Validation {
+            errors: vec![FieldError::new(
+                "id",
+                format!("doesn't match the device id \"{}\"", current.id),
+            )],
+        });
+    }
+
+    let device = to_device(state, payload, current.times)?;
+    device.write()?;
+
+    Ok((etag(&device), Json(json!(device))))
+}
+
+/// Reads the device and checks it against the `If-Match` header, if one is set
+fn read_matching(id: &DeviceId, headers: &HeaderMap) -> Result<Device, Error> {
+    let device = Device::read(id)?;
+
+    if let Some(if_match) = headers.get(IF_MATCH) {
+        let etag = device.etag();
+        let matches = if_match.to_str().is_ok_and(|if_match| {
+            if_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag == etag)
+        });
+        if !matches {
+            return Err(Error::PreconditionFailed { id: id.to_string() });
+        }
+    }
+
+    Ok(device)
+}
+
+/// Applies a JSON merge patch (RFC 7396)
+fn merge_patch(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = json!({});
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(&key);
+            } else {
+                merge_patch(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+fn etag(device: &Device) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Ok(etag) = HeaderValue::from_str(&device.etag()) {
+        headers.insert(ETAG, etag);
+    }
+    headers
+}
+
+/// Validates the payload, every invalid field is reported in [`Error::Validation`]
+pub fn to_device(
+    state: &AppState,
+    payload: DPayload,
+    times: Option<Vec<u64>>,
+) -> Result<Device, Error> {
+    let mut errors = vec![];
+
+    let id = match DeviceId::new(payload.id) {
+        Ok(id) => Some(id),
+        Err(Error::InvalidId { reason, .. }) => {
+            errors.push(FieldError::new("id", reason));
+            None
+        }
+        Err(err) => return Err(err),
+    };
+    let mac = MacAddress::from_str(&payload.mac)
+        .map_err(|_| {
+            errors.push(FieldError::new(
+                "mac",
+                format!("\"{}\" is not a valid mac address", payload.mac),
+            ));
+        })
+        .ok();
+    let ip = payload.ip.as_deref().and_then(|ip| {
+        IpNetwork::from_str(ip)
+            .map_err(|_| {
+                errors.push(FieldError::new(
+                    "ip",
+                    format!("\"{ip}\" is not a valid ip, e.g. 10.0.1.47/24"),
+                ));
+            })
+            .ok()
+    });
+    let broadcast_addr = payload.broadcast_addr.as_deref().and_then(|addr| {
+        SocketAddr::from_str(addr)
+            .map_err(|_| {
+                errors.push(FieldError::new(
+                    "broadcast_addr",
+                    format!("\"{addr}\" is not a valid address, expected ip:port"),
+                ));
+            })
+            .ok()
+    });
+
+    if let Some(agent) = &payload.via {
+        if state.config.load().agent(agent).is_none() {
+            errors.push(FieldError::new(
+                "via",
+                format!("agent \"{agent}\" isn't configured"),
+            ));
+        }
+    }
+    // The interface only has to exist where the packet is sent from
+    if let (Some(interface), None) = (&payload.interface, &payload.via) {
+        if interface_index(interface).is_err() {
+            errors.push(FieldError::new(
+                "interface",
+                format!("interface \"{interface}\" doesn't exist"),
+            ));
+        }
+    }
+
+    let (Some(id), Some(mac), true) = (id, mac, errors.is_empty()) else {
+        return Err(Error::Validation { errors });
     };
-    let mac = MacAddress::from_str(&payload.mac)?;
-    let times = Device::read(&payload.id)?.times;
 
     let device = Device {
-        id: payload.id,
+        id,
         mac,
-        broadcast_addr: payload.broadcast_addr,
+        broadcast_addr,
         ip,
         times,
+        via: payload.via,
+        interface: payload.interface,
+        meta: payload.meta,
     };
-    device.write()?;
+    if device.broadcast().is_err() {
+        return Err(Error::Validation {
+            errors: vec![FieldError::new(
+                "broadcast_addr",
+                "not set and the ip has no prefix (e.g. 10.0.1.47/24) to derive it from",
+            )],
+        });
+    }
 
-    Ok(Json(json!(device)))
+    Ok(device)
 }
@@ -1,24 +1,269 @@
 use crate::error::Error;
+use crate::inventory::{self, Format, Mode};
 use crate::storage::Device;
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::Json;
+use serde::Deserialize;
 use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
 use tracing::{debug, info};
+use utoipa::{IntoParams, ToSchema};
+
+/// Header with the number of devices matching the filters, before pagination
+const TOTAL_COUNT: &str = "x-total-count";
+
+#[derive(Deserialize, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    #[default]
+    Id,
+    /// `display_name`, falls back to the id
+    Name,
+    Owner,
+    Location,
+    Mac,
+    Ip,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct DevicesQuery {
+    /// Comma separated, devices need to have all of them
+    tag: Option<String>,
+    owner: Option<String>,
+    /// Case-insensitive search in id, name, description, location, owner, tags, mac and ip
+    q: Option<String>,
+    sort: Option<Sort>,
+    order: Option<Order>,
+    /// Starts at 1, needs `per_page`
+    #[param(value_type = Option<usize>, minimum = 1)]
+    page: Option<NonZeroUsize>,
+    /// Devices per page, all devices are returned if not set
+    #[param(value_type = Option<usize>, minimum = 1)]
+    per_page: Option<NonZeroUsize>,
+}
 
 #[utoipa::path(
     get,
     path = "/devices",
+    params(DevicesQuery),
     responses(
-        (status = 200, description = "Get an array of all `Device`s", body = [Vec<Device>])
+        (status = 200, description = "Get an array of all `Device`s matching the filters", body = Vec<Device>,
+            headers(("x-total-count" = usize, description = "Number of matching devices before pagination"))),
+        (status = 400, description = "invalid query parameters"),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 500, response = crate::error::ServerError)
     ),
     security((), ("api_key" = []))
 )]
-pub async fn get(
-) -> Result<Json<Value>, Error> {
+pub async fn get(Query(query): Query<DevicesQuery>) -> Result<(HeaderMap, Json<Value>), Error> {
     info!("get all devices");
 
-    let devices = Device::read_all()?;
+    let mut devices = Device::read_all()?;
+    devices.retain(|device| matches(device, &query));
+    sort(&mut devices, query.sort.unwrap_or_default());
+    if let Some(Order::Desc) = query.order {
+        devices.reverse();
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(TOTAL_COUNT, HeaderValue::from(devices.len()));
+
+    if let Some(per_page) = query.per_page {
+        let page = query.page.map_or(1, NonZeroUsize::get);
+        devices = devices
+            .into_iter()
+            .skip((page - 1).saturating_mul(per_page.get()))
+            .take(per_page.get())
+            .collect();
+    }
 
     debug!("got devices");
 
-    Ok(Json(json!(devices)))
+    Ok((headers, Json(json!(devices))))
+}
+
+fn matches(device: &Device, query: &DevicesQuery) -> bool {
+    let meta = &device.meta;
+
+    if let Some(tags) = &query.tag {
+        let has_tags = tags
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .all(|tag| meta.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)));
+        if !has_tags {
+            return false;
+        }
+    }
+
+    if let Some(owner) = &query.owner {
+        if !meta
+            .owner
+            .as_ref()
+            .is_some_and(|o| o.eq_ignore_ascii_case(owner))
+        {
+            return false;
+        }
+    }
+
+    if let Some(q) = &query.q {
+        let q = q.to_lowercase();
+        let fields = [
+            Some(device.id.to_string()),
+            meta.display_name.clone(),
+            meta.description.clone(),
+            meta.location.clone(),
+            meta.owner.clone(),
+            Some(device.mac.to_string()),
+            device.ip.map(|ip| ip.to_string()),
+        ];
+        let found = fields
+            .into_iter()
+            .flatten()
+            .chain(meta.tags.iter().cloned())
+            .any(|field| field.to_lowercase().contains(&q));
+        if !found {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Stable sort, devices without the field come last, ties are ordered by id
+fn sort(devices: &mut [Device], sort: Sort) {
+    fn optional<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
+    devices.sort_by(|a, b| {
+        let ordering = match sort {
+            Sort::Id => Ordering::Equal,
+            Sort::Name => a.name().to_lowercase().cmp(&b.name().to_lowercase()),
+            Sort::Owner => optional(
+                a.meta.owner.as_ref().map(|o| o.to_lowercase()),
+                b.meta.owner.as_ref().map(|o| o.to_lowercase()),
+            ),
+            Sort::Location => optional(
+                a.meta.location.as_ref().map(|l| l.to_lowercase()),
+                b.meta.location.as_ref().map(|l| l.to_lowercase()),
+            ),
+            Sort::Mac => a.mac.bytes().cmp(&b.mac.bytes()),
+            Sort::Ip => optional(a.ip.map(|ip| ip.ip()), b.ip.map(|ip| ip.ip())),
+        };
+        ordering.then_with(|| a.id.cmp(&b.id))
+    });
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ExportQuery {
+    /// Defaults to json
+    format: Option<Format>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/devices/export",
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "All devices as JSON, CSV or YAML file, ordered by id", content_type = ["application/json", "text/csv", "application/yaml"]),
+        (status = 400, description = "unknown format"),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn export(Query(query): Query<ExportQuery>) -> Result<(HeaderMap, String), Error> {
+    let format = query.format.unwrap_or_default();
+    info!(?format, "export devices");
+
+    let body = inventory::export(Device::read_all()?, format)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    if let Ok(disposition) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"devices.{}\"",
+        format.extension()
+    )) {
+        headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+
+    Ok((headers, body))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ImportQuery {
+    /// Taken from the `Content-Type` if not set, json otherwise
+    format: Option<Format>,
+    /// Defaults to merge
+    mode: Option<Mode>,
+    /// Only validate and report what would change
+    dry_run: Option<bool>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/devices/import",
+    params(ImportQuery),
+    request_body(content = String, description = "Devices as exported by `/devices/export`, as JSON, CSV (`text/csv`) or YAML (`application/yaml`)", content_type = "application/json"),
+    responses(
+        (status = 200, description = "Import applied, or dry run without invalid rows", body = Report),
+        (status = 400, description = "the file couldn't be read", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "invalid rows, nothing was changed", body = Report),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn import(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ImportQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<(StatusCode, Json<Value>), Error> {
+    let format = query
+        .format
+        .or_else(|| {
+            headers
+                .get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .and_then(Format::from_content_type)
+        })
+        .unwrap_or_default();
+
+    let report = inventory::import(
+        &state,
+        &body,
+        format,
+        query.mode.unwrap_or_default(),
+        query.dry_run.unwrap_or(false),
+    )?;
+
+    let status = if report.invalid > 0 {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(json!(report))))
 }
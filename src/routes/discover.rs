@@ -0,0 +1,136 @@
+use crate::error::Error;
+use crate::routes::device::{to_device, DPayload};
+use crate::services::discover::{self, Options};
+use crate::storage::{Device, DeviceId};
+use crate::AppState;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::State;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+
+#[derive(Deserialize, ToSchema)]
+pub struct DiscoverPayload {
+    /// Network to scan, defaults to the networks of the server's interfaces
+    cidr: Option<String>,
+    /// Ping every host first, so devices missing from the neighbor table are found
+    ping: Option<bool>,
+    /// Look up hostnames with mDNS
+    mdns: Option<bool>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/discover",
+    request_body = Option<DiscoverPayload>,
+    responses(
+        (status = 200, description = "unregistered devices found on the network", body = Vec<Candidate>),
+        (status = 400, description = "invalid cidr, IPv6 network or a cidr with more than 1024 hosts to ping", content(("application/json" = ErrorBody), ("application/problem+json" = Problem))),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn discover(
+    State(state): State<Arc<AppState>>,
+    payload: Option<Json<DiscoverPayload>>,
+) -> Result<Json<Value>, Error> {
+    let payload = payload.map_or(
+        DiscoverPayload {
+            cidr: None,
+            ping: None,
+            mdns: None,
+        },
+        |Json(payload)| payload,
+    );
+    info!("discover devices in {:?}", payload.cidr);
+
+    let probe = payload.ping.unwrap_or(false);
+    let networks = if let Some(cidr) = &payload.cidr {
+        let network = discover::parse_network(cidr)?;
+        if probe {
+            discover::check_probe_size(network)?;
+        }
+        vec![network]
+    } else {
+        discover::local_networks()?
+    };
+    debug!(?networks, "discover networks");
+
+    let candidates = discover::discover(
+        Options {
+            networks,
+            probe,
+            mdns: payload.mdns.unwrap_or(false),
+            ouifile: state.config.load().ouifile.clone(),
+        },
+        state.probe.as_ref(),
+    )
+    .await?;
+
+    Ok(Json(json!(candidates)))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ImportError {
+    id: String,
+    error: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ImportResponse {
+    imported: Vec<Device>,
+    failed: Vec<ImportError>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/discover/import",
+    request_body = Vec<DPayload>,
+    responses(
+        (status = 200, description = "add discovered devices to storage, existing and invalid devices are reported in `failed`", body = ImportResponse),
+        (status = 400, response = crate::error::BadRequest),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 415, response = crate::error::UnsupportedMediaType),
+        (status = 422, description = "the body isn't a list of devices", body = Problem, content_type = "application/problem+json"),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn import(
+    State(state): State<Arc<AppState>>,
+    payload: Result<Json<Vec<DPayload>>, JsonRejection>,
+) -> Result<Json<Value>, Error> {
+    let Json(payload) = payload?;
+    info!("import {} discovered devices", payload.len());
+
+    let mut imported = vec![];
+    let mut failed = vec![];
+    for device in payload {
+        let id = device.id.clone();
+        let result = DeviceId::new(id.as_str()).and_then(|valid| {
+            let _lock = Device::lock();
+            if Device::exists(&valid) {
+                return Err(Error::DeviceExists { id: id.clone() });
+            }
+            let device = to_device(&state, device, None)?;
+            device.write()?;
+            Ok(device)
+        });
+        match result {
+            Ok(device) => imported.push(device),
+            Err(err) => {
+                warn!(?id, "import failed: {err}");
+                failed.push(ImportError {
+                    id,
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(Json(json!(ImportResponse { imported, failed })))
+}
@@ -0,0 +1,52 @@
+use crate::error::{Error, FieldError};
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::Json;
+use mac_address::MacAddress;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::info;
+use utoipa::IntoParams;
+
+#[derive(Deserialize, IntoParams)]
+pub struct PacketQuery {
+    /// Only return packets for this mac
+    mac: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/packets",
+    params(PacketQuery),
+    responses(
+        (status = 200, description = "Magic packets received by this server, oldest first", body = Vec<Packet>),
+        (status = 422, response = crate::error::Unprocessable),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, description = "the packet listener isn't enabled", body = ErrorBody)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn get(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PacketQuery>,
+) -> Result<Json<Value>, Error> {
+    info!("get received packets");
+    let packets = state.packets.as_ref().ok_or(Error::ListenDisabled)?;
+
+    let mut packets = packets.list();
+    if let Some(mac) = query.mac {
+        let mac = MacAddress::from_str(&mac)
+            .map_err(|_| Error::Validation {
+                errors: vec![FieldError::new(
+                    "mac",
+                    format!("\"{mac}\" is not a valid mac address"),
+                )],
+            })?
+            .to_string();
+        packets.retain(|packet| packet.mac == mac);
+    }
+
+    Ok(Json(json!(packets)))
+}
@@ -0,0 +1,71 @@
+use crate::agent::{RelayPayload, RelayResponse};
+use crate::error::{Error, FieldError};
+use crate::wol::magic_packet;
+use crate::AppState;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::State;
+use axum::Json;
+use mac_address::MacAddress;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::info;
+
+#[utoipa::path(
+    post,
+    path = "/relay",
+    request_body = RelayPayload,
+    responses(
+        (status = 200, description = "send a magic packet on behalf of another webol instance", body = RelayResponse),
+        (status = 400, description = "malformed json or unknown interface", content(("application/json" = ErrorBody), ("application/problem+json" = Problem))),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 403, description = "relaying is disabled on this server", body = ErrorBody),
+        (status = 415, response = crate::error::UnsupportedMediaType),
+        (status = 422, response = crate::error::Unprocessable),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn post(
+    State(state): State<Arc<AppState>>,
+    payload: Result<Json<RelayPayload>, JsonRejection>,
+) -> Result<Json<Value>, Error> {
+    let Json(payload) = payload?;
+    if !state.config.load().relay {
+        return Err(Error::RelayDisabled);
+    }
+
+    let mut errors = vec![];
+    let mac = MacAddress::from_str(&payload.mac)
+        .map_err(|_| {
+            errors.push(FieldError::new(
+                "mac",
+                format!("\"{}\" is not a valid mac address", payload.mac),
+            ));
+        })
+        .ok();
+    let broadcast_addr = SocketAddr::from_str(&payload.broadcast_addr)
+        .map_err(|_| {
+            errors.push(FieldError::new(
+                "broadcast_addr",
+                format!(
+                    "\"{}\" is not a valid address, expected ip:port",
+                    payload.broadcast_addr
+                ),
+            ));
+        })
+        .ok();
+    let (Some(mac), Some(broadcast_addr)) = (mac, broadcast_addr) else {
+        return Err(Error::Validation { errors });
+    };
+    info!("relayed start request for {mac} on {broadcast_addr}");
+
+    let sent = state.wol.send(
+        broadcast_addr,
+        payload.interface.as_deref(),
+        &magic_packet(mac),
+    )?;
+
+    Ok(Json(json!(RelayResponse { sent })))
+}
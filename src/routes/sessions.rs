@@ -0,0 +1,120 @@
+use crate::error::Error;
+use crate::services::ping::{self, SessionState, Value as PingValue};
+use crate::storage::DeviceId;
+use crate::AppState;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tracing::info;
+use utoipa::ToSchema;
+
+/// Ping session of a device started with `"ping": true`
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct Session {
+    pub uuid: String,
+    #[schema(value_type = String, example = "desktop")]
+    pub device: DeviceId,
+    #[schema(value_type = String, example = "10.0.1.47/24")]
+    pub ip: IpNetwork,
+    /// Unix timestamp in seconds the magic packet was sent at
+    pub started: u64,
+    /// Seconds since the magic packet was sent
+    pub elapsed: u64,
+    /// Average boot time of the device in seconds
+    pub eta: u64,
+    pub state: SessionState,
+}
+
+impl Session {
+    fn new(uuid: &str, session: &PingValue) -> Self {
+        Self {
+            uuid: uuid.to_string(),
+            device: session.device.clone(),
+            ip: session.ip,
+            started: session
+                .started
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |started| started.as_secs()),
+            elapsed: session
+                .started
+                .elapsed()
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            eta: session.eta,
+            state: session.state(),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    responses(
+        (status = 200, description = "running ping sessions and finished ones within `pingretention` seconds, oldest first", body = Vec<Session>),
+        (status = 401, response = crate::error::Unauthorized)
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn list(State(state): State<Arc<AppState>>) -> Json<Value> {
+    let mut sessions = state
+        .sessions
+        .list()
+        .iter()
+        .map(|(uuid, session)| Session::new(uuid, session))
+        .collect::<Vec<_>>();
+    sessions.sort_by_key(|session| session.started);
+    Json(json!(sessions))
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions/{uuid}",
+    responses(
+        (status = 200, description = "ping session with the given uuid", body = Session),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, description = "no session with this uuid, it may have expired", body = ErrorBody)
+    ),
+    params(
+        ("uuid" = String, Path, description = "session uuid returned by `/start/{id}`")
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn get(
+    State(state): State<Arc<AppState>>,
+    Path(uuid): Path<String>,
+) -> Result<Json<Value>, Error> {
+    let session = state
+        .sessions
+        .get(&uuid)
+        .map(|session| Session::new(&uuid, &session))
+        .ok_or(Error::SessionNotFound { uuid })?;
+    Ok(Json(json!(session)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/sessions/{uuid}",
+    responses(
+        (status = 204, description = "stop probing the device and remove the session, status watchers get `cancelled_<uuid>`"),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, description = "no session with this uuid, it may have expired", body = ErrorBody)
+    ),
+    params(
+        ("uuid" = String, Path, description = "session uuid returned by `/start/{id}`")
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn delete(
+    State(state): State<Arc<AppState>>,
+    Path(uuid): Path<String>,
+) -> Result<StatusCode, Error> {
+    info!("cancel ping session {uuid}");
+    if !ping::cancel(&state.sessions, &uuid) {
+        return Err(Error::SessionNotFound { uuid });
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
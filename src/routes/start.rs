@@ -1,22 +1,32 @@
-use crate::storage::Device;
+use crate::agent;
+use crate::config::{Check, Limit};
 use crate::error::Error;
-use crate::services::ping::Value as PingValue;
-use crate::wol::{create_buffer, send_packet};
-use axum::extract::{Path, State};
+use crate::services::sessions::Joined;
+use crate::storage::{Device, DeviceId};
+use crate::wol::magic_packet;
+use axum::extract::{ConnectInfo, Path, Query, State};
 use axum::Json;
+use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tracing::{debug, info};
-use utoipa::ToSchema;
-use uuid::Uuid;
+use utoipa::{IntoParams, ToSchema};
 
 #[utoipa::path(
     post,
     path = "/start/{id}",
     request_body = Option<SPayload>,
     responses(
-        (status = 200, description = "start device with the given id", body = [Response])
+        (status = 200, description = "start device with the given id", body = Response),
+        (status = 400, description = "invalid id, no broadcast address, unknown relay agent, ping requested for a device without ip or with an IPv6 ip and an arp check", content(("application/json" = ErrorBody), ("application/problem+json" = Problem))),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, response = crate::error::NotFound),
+        (status = 429, description = "rate limit of the client or device exceeded", body = ErrorBody,
+            headers(("retry-after" = u64, description = "Seconds until the next start is accepted"))),
+        (status = 500, response = crate::error::ServerError),
+        (status = 502, description = "the relay agent couldn't be reached or couldn't start the device", body = ErrorBody)
     ),
     params(
         ("id" = String, Path, description = "device id")
@@ -26,126 +36,216 @@ use uuid::Uuid;
 pub async fn post(
     State(state): State<Arc<crate::AppState>>,
     Path(id): Path<String>,
+    client: Option<ConnectInfo<SocketAddr>>,
     payload: Option<Json<SPayload>>,
 ) -> Result<Json<Value>, Error> {
-    send_wol(state, &id, payload)
+    let payload = payload.map(|Json(payload)| payload).unwrap_or_default();
+    send_wol(state, &id, client, payload).await
 }
 
 #[utoipa::path(
     get,
     path = "/start/{id}",
     responses(
-        (status = 200, description = "start the device with the given id", body = [Response])
+        (status = 200, description = "start the device with the given id, can be disabled with `wake.get`", body = Response),
+        (status = 400, description = "invalid id, no broadcast address, unknown relay agent or ping requested for a device without ip", content(("application/json" = ErrorBody), ("application/problem+json" = Problem))),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, response = crate::error::NotFound),
+        (status = 405, description = "starting devices with GET is disabled", body = ErrorBody),
+        (status = 429, description = "rate limit of the client or device exceeded", body = ErrorBody,
+            headers(("retry-after" = u64, description = "Seconds until the next start is accepted"))),
+        (status = 500, response = crate::error::ServerError),
+        (status = 502, description = "the relay agent couldn't be reached or couldn't start the device", body = ErrorBody)
     ),
     params(
-        ("id" = String, Path, description = "device id")
+        ("id" = String, Path, description = "device id"),
+        StartQuery
     ),
     security((), ("api_key" = []))
 )]
 pub async fn get(
     State(state): State<Arc<crate::AppState>>,
     Path(id): Path<String>,
+    Query(query): Query<StartQuery>,
+    client: Option<ConnectInfo<SocketAddr>>,
 ) -> Result<Json<Value>, Error> {
-    send_wol(state, &id, None)
+    if !state.config.load().wake.get {
+        return Err(Error::GetStartDisabled);
+    }
+    let payload = SPayload {
+        ping: None,
+        force: query.force,
+    };
+    send_wol(state, &id, client, payload).await
 }
 
-fn send_wol(
+/// Starts the device `id`, shared by all start endpoints
+pub async fn send_wol(
     state: Arc<crate::AppState>,
     id: &str,
-    payload: Option<Json<SPayload>>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    payload: SPayload,
 ) -> Result<Json<Value>, Error> {
     info!("start request for {id}");
-    let device = Device::read(id)?;
+    let limits = state.config.load().ratelimit.clone();
+    if let (Some(limit), Some(ConnectInfo(addr))) = (limits.client, client) {
+        state
+            .limits
+            .check(&format!("client:{}", addr.ip()), limit)?;
+    }
 
-    info!("starting {}", device.id);
+    let device = Device::read(&DeviceId::new(id)?)?;
 
-    let bind_addr = "0.0.0.0:0";
+    // Reserved before the magic packet is sent, so of concurrent starts only one sends it
+    let ping = payload.ping.is_some_and(|ping| ping);
+    let ipv6 = device.ip.is_some_and(|ip| ip.is_ipv6());
+    if ping && ipv6 && state.config.load().pingprobes.contains(&Check::Arp) {
+        return Err(Error::ArpIpv6 {
+            id: device.id.to_string(),
+        });
+    }
+    let session = device.ip.filter(|_| ping).map(|ip| {
+        let eta = get_eta(device.times.clone());
+        (ip, eta, state.sessions.join_or_start(&device.id, ip, eta))
+    });
+    let booting = match &session {
+        Some((_, _, Joined::Running { uuid, eta })) => Some((uuid.clone(), *eta)),
+        Some((_, _, Joined::Started { .. })) => None,
+        None => state.sessions.booting(&device.id),
+    };
+    if let Some((uuid, eta)) = booting.filter(|_| !payload.force.unwrap_or(false)) {
+        info!(uuid, "{} is already booting, start debounced", device.id);
+        return Ok(Json(json!(Response {
+            id: device.id.to_string(),
+            boot: false,
+            uuid: Some(uuid),
+            eta: Some(eta),
+            via: device.via,
+        })));
+    }
 
-    send_packet(
-        bind_addr,
-        &device.broadcast_addr.to_string(),
-        &create_buffer(&device.mac.to_string())?
-    )?;
-    let dev_id = device.id.clone();
-    let uuid = if let Some(pl) = payload {
-        if pl.ping.is_some_and(|ping| ping) {
-            if device.ip.is_none() {
-                return Err(Error::NoIpOnPing);
+    let via = match wake(&state, &device, limits.device).await {
+        Ok(via) => via,
+        Err(err) => {
+            // Nobody got the uuid yet, the next start reserves a new session
+            if let Some((_, _, Joined::Started { uuid })) = &session {
+                state.sessions.remove(uuid);
             }
-            Some(setup_ping(state, device))
-        } else {
-            None
+            return Err(err);
         }
-    } else {
-        None
+    };
+    if ping && session.is_none() {
+        return Err(Error::NoIpOnPing);
+    }
+
+    let dev_id = device.id.clone();
+    let (uuid, eta) = match session {
+        Some((ip, eta, Joined::Started { uuid })) => {
+            spawn_ping(&state, device.id, ip, eta, &uuid);
+            (Some(uuid), Some(eta))
+        }
+        Some((_, _, Joined::Running { uuid, eta })) => {
+            debug!("service already exists");
+            (Some(uuid), Some(eta))
+        }
+        None => (None, None),
     };
 
     Ok(Json(json!(Response {
-        id: dev_id,
+        id: dev_id.to_string(),
         boot: true,
-        uuid
+        uuid,
+        eta,
+        via,
     })))
 }
 
-fn setup_ping(state: Arc<crate::AppState>, device: Device) -> String {
-    let mut uuid: Option<String> = None;
-    // Safe: Only called when ip is set
-    let ip = device.ip.unwrap();
-    for (key, value) in state.ping_map.clone() {
-        if value.ip == ip {
-            debug!("service already exists");
-            uuid = Some(key);
-            break;
-        }
+/// Sends the magic packet directly or through the agent of the device, returns the agent
+async fn wake(
+    state: &crate::AppState,
+    device: &Device,
+    limit: Option<Limit>,
+) -> Result<Option<String>, Error> {
+    if let Some(limit) = limit {
+        state
+            .limits
+            .check(&format!("device:{}", device.id), limit)?;
     }
-    let uuid_gen = match uuid {
-        Some(u) => u,
-        None => Uuid::new_v4().to_string(),
-    };
-    let uuid_ret = uuid_gen.clone();
 
+    info!("starting {}", device.id);
+
+    if let Some(name) = &device.via {
+        let agent =
+            state
+                .config
+                .load()
+                .agent(name)
+                .cloned()
+                .ok_or_else(|| Error::UnknownAgent {
+                    agent: name.clone(),
+                })?;
+        let client = state.agents.client(&agent).await?;
+        agent::wake(&agent, &client, device).await?;
+        Ok(Some(agent.name))
+    } else {
+        state.wol.send(
+            device.broadcast()?,
+            device.interface.as_deref(),
+            &magic_packet(device.mac),
+        )?;
+        Ok(None)
+    }
+}
+
+/// Starts the probe task of the session `uuid` reserved for the device
+fn spawn_ping(state: &Arc<crate::AppState>, id: DeviceId, ip: IpNetwork, eta: u64, uuid: &str) {
     debug!("init ping service");
-    state.ping_map.insert(
-        uuid_gen.clone(),
-        PingValue {
-            ip,
-            eta: get_eta(device.clone().times),
-            online: false,
-        },
-    );
-
-    tokio::spawn(async move {
+    let task_state = state.clone();
+    let task_uuid = uuid.to_string();
+    let task = tokio::spawn(async move {
         crate::services::ping::spawn(
-            state.ping_send.clone(),
-            &state.config,
-            device,
-            uuid_gen,
-            &state.ping_map,
+            &task_state.config.load_full(),
+            id,
+            ip.ip(),
+            eta,
+            task_uuid,
+            &task_state.sessions,
+            task_state.probe.as_ref(),
         )
         .await;
     });
-
-    uuid_ret
+    state.sessions.set_task(uuid, task.abort_handle());
 }
 
 fn get_eta(times: Option<Vec<u64>>) -> u64 {
-    let times = if let Some(times) = times {
-        times
-    } else {
-        vec![0]
-    };
-
-    times.iter().sum::<u64>() / u64::try_from(times.len()).unwrap()
+    let times = times.unwrap_or_default();
+    let count = u64::try_from(times.len()).unwrap_or(u64::MAX);
+    times.iter().sum::<u64>().checked_div(count).unwrap_or(0)
 }
 
-#[derive(Deserialize, ToSchema)]
+#[derive(Deserialize, Default, ToSchema)]
 pub struct SPayload {
-    ping: Option<bool>,
+    pub ping: Option<bool>,
+    /// Send the magic packet even if the device is already booting
+    pub force: Option<bool>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StartQuery {
+    /// Send the magic packet even if the device is already booting
+    force: Option<bool>,
 }
 
-#[derive(Serialize, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct Response {
-    id: String,
-    boot: bool,
-    uuid: Option<String>,
+    pub id: String,
+    /// `false` if the device was already booting and no magic packet was sent
+    pub boot: bool,
+    /// Passed to `/status` to follow the start, only set with `"ping": true`
+    pub uuid: Option<String>,
+    /// Average boot time in seconds, set with `uuid`
+    pub eta: Option<u64>,
+    /// Relay agent that sent the magic packet
+    pub via: Option<String>,
 }
@@ -1,63 +1,134 @@
-use crate::services::ping::BroadcastCommand;
+use crate::error::Error;
 use crate::AppState;
-use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{State, WebSocketUpgrade};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use axum::extract::{Query, State, WebSocketUpgrade};
 use axum::response::Response;
+use serde::Deserialize;
+use serde_json::json;
 use std::sync::Arc;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
-pub async fn status(State(state): State<Arc<AppState>>, ws: WebSocketUpgrade) -> Response {
-    ws.on_upgrade(move |socket| websocket(socket, state))
+/// Control frames carry at most 125 bytes, two of them are the close code
+const MAX_REASON: usize = 123;
+
+/// Websocket that reports when a device started with `"ping": true` is online.
+///
+/// The client sends the `uuid` returned by `/start/{id}` as text message. The server answers with
+/// `eta_<seconds>_<uuid>`, the average boot time of the device, followed by one of
+/// `start_<uuid>` once the device passed all `pingprobes`, `timeout_<uuid>` if it doesn't within
+/// `pingtimeout` minutes, `error_<uuid>` if a probe failed or `cancelled_<uuid>` if the session was
+/// cancelled, and closes the connection. Unknown uuids are answered with `notfound_<uuid>`.
+/// Errors close the connection with a close frame that has the error, cut to 123 bytes, as reason.
+///
+/// With `?progress=true` every probe round and the result are preceded by
+/// `progress_<json>_<uuid>`, the JSON has `state`, `elapsed` seconds, `attempts`, `percent` of the
+/// eta, the `error` of a failed probe, the last check that didn't pass yet as `failure` and the
+/// reached `milestones`.
+#[utoipa::path(
+    get,
+    path = "/status",
+    responses(
+        (status = 101, description = "switching to the websocket protocol, see the description of this path"),
+        (status = 400, description = "not a websocket upgrade request"),
+        (status = 401, response = crate::error::Unauthorized)
+    ),
+    params(
+        ("progress" = Option<bool>, Query, description = "also send progress messages")
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn status(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StatusQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let progress = query.progress.unwrap_or(false);
+    ws.on_upgrade(move |socket| websocket(socket, state, progress))
+}
+
+#[derive(Deserialize, Default)]
+pub struct StatusQuery {
+    progress: Option<bool>,
 }
 
-pub async fn websocket(mut socket: WebSocket, state: Arc<AppState>) {
+pub async fn websocket(mut socket: WebSocket, state: Arc<AppState>, progress: bool) {
+    let result = watch(&mut socket, &state, progress).await;
+    let frame = match result {
+        Ok(()) => None,
+        Err(err) => {
+            warn!("status websocket: {err}");
+            let code = match err {
+                Error::StatusMessage => close_code::UNSUPPORTED,
+                _ => close_code::ERROR,
+            };
+            Some(CloseFrame {
+                code,
+                reason: reason(&err).into(),
+            })
+        }
+    };
+
+    let _ = socket.send(Message::Close(frame)).await;
+}
+
+/// `err` as close reason, cut on a char boundary if it's too long
+fn reason(err: &Error) -> String {
+    let mut reason = err.to_string();
+    if reason.len() > MAX_REASON {
+        let end = (0..=MAX_REASON)
+            .rev()
+            .find(|end| reason.is_char_boundary(*end))
+            .unwrap_or(0);
+        reason.truncate(end);
+    }
+    reason
+}
+
+/// Answers the uuid sent by the client with the eta and result of its session
+async fn watch(socket: &mut WebSocket, state: &AppState, progress: bool) -> Result<(), Error> {
     trace!("wait for ws message (uuid)");
-    let msg = socket.recv().await;
-    let uuid = msg.unwrap().unwrap().into_text().unwrap();
+    let uuid = match socket.recv().await.transpose()? {
+        Some(Message::Text(uuid)) => uuid,
+        None | Some(Message::Close(_)) => {
+            debug!("status client disconnected");
+            return Ok(());
+        }
+        Some(_) => return Err(Error::StatusMessage),
+    };
 
     trace!("Search for uuid: {}", uuid);
 
-
-    let device_exists = state.ping_map.contains_key(&uuid);
-    if device_exists {
-        let eta = state.ping_map.get(&uuid).unwrap().eta;
-        let _ = socket
-            .send(Message::Text(format!("eta_{eta}_{uuid}")))
-            .await;
-        let _ = socket
-            .send(receive_ping_broadcast(state.clone(), uuid).await)
-            .await;
-    } else {
+    let Some(session) = state.sessions.get(&uuid) else {
         debug!("didn't find any device");
-        let _ = socket.send(Message::Text(format!("notfound_{uuid}"))).await;
+        socket
+            .send(Message::Text(format!("notfound_{uuid}")))
+            .await?;
+        return Ok(());
     };
+    // Subscribed together with the lookup, a transition right after it is still seen
+    let mut rx = session.watch.subscribe();
+    debug!("got device: {} (state: {:?})", session.ip, session.state());
 
-    let _ = socket.close().await;
-}
+    socket
+        .send(Message::Text(format!("eta_{}_{uuid}", session.eta)))
+        .await?;
+    drop(session);
 
-async fn receive_ping_broadcast(state: Arc<AppState>, uuid: String) -> Message {
-    let pm = state.ping_map.clone().into_read_only();
-    let device = pm.get(&uuid).expect("fatal error");
-    debug!("got device: {} (online: {})", device.ip, device.online);
-    if device.online {
-        debug!("already started");
-        Message::Text(BroadcastCommand::success(uuid).to_string())
-    } else {
-        loop {
-            trace!("wait for tx message");
-            let message = state
-                .ping_send
-                .subscribe()
-                .recv()
-                .await
-                .expect("fatal error");
-            trace!("got message {:?}", message);
-
-            if message.uuid != uuid {
-                continue;
-            }
-            trace!("message == uuid success");
-            return Message::Text(message.to_string());
+    loop {
+        let current = rx.borrow_and_update().clone();
+        if progress {
+            trace!(?current, "send progress");
+            socket
+                .send(Message::Text(format!("progress_{}_{uuid}", json!(current))))
+                .await?;
         }
+        if let Some(message) = current.state.message(&uuid) {
+            trace!(state = ?current.state, "session finished");
+            socket.send(Message::Text(message)).await?;
+            return Ok(());
+        }
+
+        trace!("wait for session progress");
+        rx.changed().await.map_err(|_| Error::StatusClosed)?;
     }
 }
@@ -0,0 +1,117 @@
+use crate::error::Error;
+use crate::routes::start::{send_wol, SPayload};
+use crate::storage::{Device, DeviceId};
+use crate::AppState;
+use axum::extract::{ConnectInfo, Path, State};
+use axum::response::Html;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::info;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct TokenResponse {
+    /// Confirmed with `POST /wake/{token}`
+    pub token: String,
+    /// Unix timestamp in seconds, after that the token isn't accepted
+    pub expires: u64,
+    /// Pre-signed url, opening it shows a confirmation page and doesn't start the device
+    pub url: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/start/{id}/token",
+    responses(
+        (status = 200, description = "single-use token to start the device with `POST /wake/{token}`, valid for `wake.tokenttl` seconds", body = TokenResponse),
+        (status = 400, response = crate::error::BadRequest),
+        (status = 401, response = crate::error::Unauthorized),
+        (status = 404, response = crate::error::NotFound),
+        (status = 500, response = crate::error::ServerError)
+    ),
+    params(
+        ("id" = String, Path, description = "device id")
+    ),
+    security((), ("api_key" = []))
+)]
+pub async fn token(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, Error> {
+    let id = DeviceId::new(id)?;
+    if !Device::exists(&id) {
+        return Err(Error::DeviceNotFound { id: id.to_string() });
+    }
+
+    info!("issue wake token for {id}");
+    let (token, expires) = state.tokens.issue(&state.config.load().wake, &id)?;
+    Ok(Json(json!(TokenResponse {
+        url: format!("/wake/{token}"),
+        token,
+        expires,
+    })))
+}
+
+/// Confirmation page of a pre-signed url, link previews and crawlers can open it without effect
+#[utoipa::path(
+    get,
+    path = "/wake/{token}",
+    responses(
+        (status = 200, description = "html page with a button to start the device", content_type = "text/html"),
+        (status = 403, description = "the token is invalid, expired or already used", body = ErrorBody)
+    ),
+    params(
+        ("token" = String, Path, description = "wake token")
+    )
+)]
+pub async fn page(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+) -> Result<Html<String>, Error> {
+    let token = state.tokens.check(&state.config.load().wake, &token)?;
+    // Device ids are restricted to characters that don't need escaping
+    Ok(Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><meta name="robots" content="noindex"><title>Start {id}</title></head>
+<body>
+<form method="post"><button type="submit">Start {id}</button></form>
+</body>
+</html>
+"#,
+        id = token.id
+    )))
+}
+
+#[utoipa::path(
+    post,
+    path = "/wake/{token}",
+    request_body = Option<SPayload>,
+    responses(
+        (status = 200, description = "start the device of the token, the token can't be used again", body = Response),
+        (status = 400, description = "no broadcast address, unknown relay agent or ping requested for a device without ip", body = ErrorBody),
+        (status = 403, description = "the token is invalid, expired or already used", body = ErrorBody),
+        (status = 404, response = crate::error::NotFound),
+        (status = 429, description = "rate limit of the client or device exceeded", body = ErrorBody,
+            headers(("retry-after" = u64, description = "Seconds until the next start is accepted"))),
+        (status = 500, response = crate::error::ServerError),
+        (status = 502, description = "the relay agent couldn't be reached or couldn't start the device", body = ErrorBody)
+    ),
+    params(
+        ("token" = String, Path, description = "wake token")
+    )
+)]
+pub async fn post(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    payload: Option<Json<SPayload>>,
+) -> Result<Json<Value>, Error> {
+    let token = state.tokens.redeem(&state.config.load().wake, &token)?;
+    info!("start {} with wake token", token.id);
+    let payload = payload.map(|Json(payload)| payload).unwrap_or_default();
+    send_wol(state, &token.id, client, payload).await
+}
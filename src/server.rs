@@ -0,0 +1,175 @@
+use crate::{
+    config::{ListenAddr, Listener},
+    tls::{ClientCert, Tls},
+    AppState,
+};
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header::HOST, uri::Authority, HeaderMap, Uri},
+    response::Redirect,
+    Router,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use std::{
+    fmt::Debug,
+    fs::{self, Permissions},
+    io,
+    net::SocketAddr,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+};
+use tower::Service;
+use tracing::{debug, error, info};
+
+/// Binds the listener and serves `app` on it
+pub async fn serve(listener: Listener, app: Router, state: Arc<AppState>) -> io::Result<()> {
+    match listener.listen_addr() {
+        ListenAddr::Tcp(addr) => {
+            let tcp = TcpListener::bind(&addr).await?;
+            match state.tls.as_ref().filter(|_| listener.tls) {
+                Some(tls) => {
+                    info!(
+                        admin = listener.admin,
+                        auth = listener.auth,
+                        "start server on https://{addr}"
+                    );
+                    serve_tls(tcp, app, tls).await
+                }
+                None => {
+                    info!(
+                        admin = listener.admin,
+                        auth = listener.auth,
+                        "start server on http://{addr}"
+                    );
+                    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+                    axum::serve(tcp, app).await
+                }
+            }
+        }
+        ListenAddr::Unix(path) => {
+            let unix = bind_unix(&path, listener.mode)?;
+            info!(
+                admin = listener.admin,
+                auth = listener.auth,
+                "start server on unix:{}",
+                path.display()
+            );
+            loop {
+                let (stream, addr) = match unix.accept().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        error!("accept connection: {err}");
+                        continue;
+                    }
+                };
+                tokio::spawn(serve_connection(stream, addr, None, app.clone(), false));
+            }
+        }
+    }
+}
+
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    // A socket left over from a previous run makes the bind fail, anything else is kept
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// Serves `app` over TLS, the certificates are taken from `tls` for every new connection
+async fn serve_tls(listener: TcpListener, app: Router, tls: &Tls) -> io::Result<()> {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("accept connection: {err}");
+                continue;
+            }
+        };
+        let acceptor = tls.acceptor();
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    debug!(?addr, "tls handshake failed: {err}");
+                    return;
+                }
+            };
+            let client_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .is_some_and(|certs| !certs.is_empty());
+
+            serve_connection(stream, addr, Some(addr), app, client_cert).await;
+        });
+    }
+}
+
+/// Serves one connection, `peer` is passed to the handlers as [`ConnectInfo`] for rate limits
+async fn serve_connection<S, A>(
+    stream: S,
+    addr: A,
+    peer: Option<SocketAddr>,
+    app: Router,
+    client_cert: bool,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    A: Debug,
+{
+    let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+        if let Some(peer) = peer {
+            request.extensions_mut().insert(ConnectInfo(peer));
+        }
+        if client_cert {
+            request.extensions_mut().insert(ClientCert);
+        }
+        app.clone().call(request)
+    });
+
+    if let Err(err) = Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+        .await
+    {
+        debug!(?addr, "connection closed: {err}");
+    }
+}
+
+/// Plain HTTP listener that redirects every request to the HTTPS `port`
+pub async fn redirect(addr: String, port: u16) -> io::Result<()> {
+    let app = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        let host = headers
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())
+            .map_or_else(|| "localhost".to_string(), |host| host.host().to_string());
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        Redirect::permanent(&format!("https://{host}:{port}{path}"))
+    });
+
+    info!("start https redirect on {}", addr);
+    let listener = TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await
+}
@@ -1 +1,6 @@
+pub mod discover;
+pub mod listen;
 pub mod ping;
+pub mod ratelimit;
+pub mod reload;
+pub mod sessions;
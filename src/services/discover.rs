@@ -0,0 +1,287 @@
+use crate::config::Check;
+use crate::error::Error;
+use crate::services::ping::{Outcome, Probe};
+use crate::storage::{Device, DeviceId};
+use futures_util::future::join_all;
+use ipnetwork::{IpNetwork, Ipv4Network};
+use mac_address::MacAddress;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use nix::ifaddrs::getifaddrs;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+    time::Duration,
+};
+use tokio::{task::JoinSet, time::timeout};
+use tracing::{debug, trace, warn};
+use utoipa::ToSchema;
+
+const ARP_TABLE: &str = "/proc/net/arp";
+
+/// Probing every host of larger networks takes too long for a request
+const MAX_PROBE_HOSTS: u32 = 1024;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const MDNS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Service types most hosts announce themselves with
+const MDNS_SERVICES: [&str; 4] = [
+    "_workstation._tcp.local.",
+    "_device-info._tcp.local.",
+    "_ssh._tcp.local.",
+    "_smb._tcp.local.",
+];
+
+/// Locations of the IEEE OUI list on common distributions
+const OUI_FILES: [&str; 3] = [
+    "/usr/share/ieee-data/oui.txt",
+    "/usr/share/misc/oui.txt",
+    "/usr/share/hwdata/oui.txt",
+];
+
+/// A device found on the network that isn't registered yet.
+///
+/// The fields match `DPayload`, so candidates can be sent back to `/discover/import` as they are.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Candidate {
+    /// Suggested device id, from the hostname or the ip
+    pub id: String,
+    pub mac: String,
+    /// Ip with the prefix of the scanned network
+    pub ip: String,
+    pub hostname: Option<String>,
+    pub vendor: Option<String>,
+}
+
+pub struct Options {
+    pub networks: Vec<Ipv4Network>,
+    pub probe: bool,
+    pub mdns: bool,
+    pub ouifile: Option<String>,
+}
+
+pub async fn discover(options: Options, pinger: &dyn Probe) -> Result<Vec<Candidate>, Error> {
+    if options.probe {
+        for network in &options.networks {
+            if network.size() > MAX_PROBE_HOSTS {
+                // Networks of the interfaces, e.g. a docker bridge, are still read from the
+                // neighbor table
+                warn!(%network, "more than {MAX_PROBE_HOSTS} hosts, network isn't probed");
+                continue;
+            }
+            probe(*network, pinger).await;
+        }
+    }
+
+    let registered = Device::read_all()?
+        .into_iter()
+        .map(|device| device.mac)
+        .collect::<HashSet<_>>();
+
+    let neighbors = neighbors()?
+        .into_iter()
+        .filter_map(|(ip, mac)| {
+            let network = options.networks.iter().find(|net| net.contains(ip))?;
+            (!registered.contains(&mac)).then_some((ip, (mac, network.prefix())))
+        })
+        .collect::<BTreeMap<_, _>>();
+    debug!("found {} unregistered neighbors", neighbors.len());
+
+    let hostnames = if options.mdns && !neighbors.is_empty() {
+        mdns_hostnames().await
+    } else {
+        HashMap::new()
+    };
+    let vendors = if neighbors.is_empty() {
+        HashMap::new()
+    } else {
+        oui_table(options.ouifile.as_deref())
+    };
+
+    Ok(neighbors
+        .into_iter()
+        .map(|(ip, (mac, prefix))| {
+            let hostname = hostnames.get(&ip).cloned();
+            let oui = mac.bytes();
+            Candidate {
+                id: hostname
+                    .as_deref()
+                    .and_then(DeviceId::sanitize)
+                    .map_or_else(|| ip.to_string().replace('.', "-"), String::from),
+                mac: mac.to_string(),
+                ip: format!("{ip}/{prefix}"),
+                hostname,
+                vendor: vendors.get(&[oui[0], oui[1], oui[2]]).cloned(),
+            }
+        })
+        .collect())
+}
+
+/// IPv4 networks of the local interfaces, loopback excluded
+pub fn local_networks() -> Result<Vec<Ipv4Network>, Error> {
+    let mut networks = vec![];
+    for ifaddr in getifaddrs().map_err(std::io::Error::from)? {
+        let (Some(addr), Some(mask)) = (ifaddr.address, ifaddr.netmask) else {
+            continue;
+        };
+        let (Some(addr), Some(mask)) = (addr.as_sockaddr_in(), mask.as_sockaddr_in()) else {
+            continue;
+        };
+        let ip = addr.ip();
+        if ip.is_loopback() {
+            continue;
+        }
+        let network = Ipv4Network::with_netmask(ip, mask.ip())?;
+        let network = Ipv4Network::new(network.network(), network.prefix())?;
+        if !networks.contains(&network) {
+            networks.push(network);
+        }
+    }
+    Ok(networks)
+}
+
+/// Parses `cidr` into an IPv4 network, the host bits are dropped
+pub fn parse_network(cidr: &str) -> Result<Ipv4Network, Error> {
+    match IpNetwork::from_str(cidr)? {
+        IpNetwork::V4(net) => Ok(Ipv4Network::new(net.network(), net.prefix())?),
+        IpNetwork::V6(_) => Err(Error::Ipv6Discovery),
+    }
+}
+
+/// Fails for networks with more hosts than are probed
+pub fn check_probe_size(network: Ipv4Network) -> Result<(), Error> {
+    if network.size() > MAX_PROBE_HOSTS {
+        return Err(Error::NetworkTooLarge {
+            network: network.to_string(),
+            max: MAX_PROBE_HOSTS,
+        });
+    }
+    Ok(())
+}
+
+/// Pings every host so the kernel learns their mac addresses
+async fn probe(network: Ipv4Network, pinger: &dyn Probe) {
+    debug!(%network, "probe network");
+
+    let probes = network
+        .iter()
+        .filter(|ip| *ip != network.network() && *ip != network.broadcast())
+        .map(|ip| async move {
+            match timeout(PROBE_TIMEOUT, pinger.probe(IpAddr::V4(ip), Check::Icmp)).await {
+                Ok(Ok(Outcome::Passed(_))) => true,
+                Ok(Ok(Outcome::Failed(_))) | Err(_) => false,
+                Ok(Err(err)) => {
+                    trace!(%ip, "probe failed: {err}");
+                    false
+                }
+            }
+        });
+    let online = join_all(probes)
+        .await
+        .into_iter()
+        .filter(|online| *online)
+        .count();
+    debug!(%network, online, "probe done");
+}
+
+/// Reads the kernel neighbor table, incomplete entries are skipped
+fn neighbors() -> Result<Vec<(Ipv4Addr, MacAddress)>, Error> {
+    let table = fs::read_to_string(ARP_TABLE)?;
+    Ok(parse_arp_table(&table))
+}
+
+fn parse_arp_table(table: &str) -> Vec<(Ipv4Addr, MacAddress)> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let columns = line.split_whitespace().collect::<Vec<_>>();
+            let (ip, flags, mac) = (columns.first()?, columns.get(2)?, columns.get(3)?);
+            // 0x2 is ATF_COM, the entry is complete
+            let flags = u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok()?;
+            if flags & 0x2 == 0 {
+                return None;
+            }
+            let mac = MacAddress::from_str(mac).ok()?;
+            (mac.bytes() != [0; 6]).then_some((Ipv4Addr::from_str(ip).ok()?, mac))
+        })
+        .collect()
+}
+
+/// Browses common mDNS services for a while and maps the announced addresses to hostnames
+async fn mdns_hostnames() -> HashMap<Ipv4Addr, String> {
+    let mut hostnames = HashMap::new();
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(err) => {
+            warn!("mdns unavailable: {err}");
+            return hostnames;
+        }
+    };
+
+    let mut browsers = JoinSet::new();
+    for service in MDNS_SERVICES {
+        let Ok(receiver) = daemon.browse(service) else {
+            continue;
+        };
+        browsers.spawn(async move {
+            let mut found = vec![];
+            let _ = timeout(MDNS_TIMEOUT, async {
+                while let Ok(event) = receiver.recv_async().await {
+                    let ServiceEvent::ServiceResolved(info) = event else {
+                        continue;
+                    };
+                    let hostname = info.get_hostname().trim_end_matches('.');
+                    let hostname = hostname.trim_end_matches(".local");
+                    for ip in info.get_addresses_v4() {
+                        trace!(%ip, hostname, "mdns resolved");
+                        found.push((*ip, hostname.to_string()));
+                    }
+                }
+            })
+            .await;
+            found
+        });
+    }
+
+    while let Some(found) = browsers.join_next().await {
+        hostnames.extend(found.unwrap_or_default());
+    }
+
+    if let Err(err) = daemon.shutdown() {
+        debug!("mdns shutdown: {err}");
+    }
+    hostnames
+}
+
+/// Loads the IEEE OUI list, vendors stay empty if none is found
+fn oui_table(ouifile: Option<&str>) -> HashMap<[u8; 3], String> {
+    let files = ouifile.map_or_else(|| OUI_FILES.to_vec(), |file| vec![file]);
+    for file in files {
+        if let Ok(list) = fs::read_to_string(file) {
+            debug!(file, "loaded oui list");
+            return parse_oui_list(&list);
+        }
+    }
+    debug!("no oui list found, vendors are unknown");
+    HashMap::new()
+}
+
+/// Parses the `XX-XX-XX   (hex)  Vendor` lines of the IEEE OUI list
+fn parse_oui_list(list: &str) -> HashMap<[u8; 3], String> {
+    list.lines()
+        .filter_map(|line| {
+            let (prefix, vendor) = line.split_once("(hex)")?;
+            let mut oui = [0; 3];
+            let mut parts = prefix.trim().split('-');
+            for byte in &mut oui {
+                *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+            }
+            Some((oui, vendor.trim().to_string()))
+        })
+        .collect()
+}
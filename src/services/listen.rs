@@ -0,0 +1,235 @@
+use crate::config::Listen;
+use crate::error::Error;
+use crate::storage::Device;
+use mac_address::MacAddress;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Read,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::UdpSocket, task::JoinSet};
+use tracing::{debug, error, info, warn};
+use utoipa::ToSchema;
+
+/// Ethertype of magic packets sent directly in an ethernet frame
+const ETHERTYPE_WOL: u16 = 0x0842;
+
+const ETHERNET_HEADER: usize = 14;
+
+/// Six `0xff` bytes followed by the target mac repeated 16 times
+const MAGIC_LEN: usize = 6 + 16 * 6;
+
+/// Received packets kept for `GET /packets`, older ones are dropped
+const CAPACITY: usize = 100;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Packet {
+    /// Unix timestamp in seconds
+    pub received: u64,
+    /// `ip:port` for UDP packets, the sender's mac for raw frames
+    pub source: String,
+    /// `udp/<port>` or `raw`
+    pub transport: String,
+    /// Target mac of the magic packet
+    pub mac: String,
+    /// Id of the registered device with this mac
+    pub device: Option<String>,
+}
+
+/// The most recently received magic packets
+#[derive(Default)]
+pub struct Packets(Mutex<VecDeque<Packet>>);
+
+impl Packets {
+    pub fn push(&self, packet: Packet) {
+        let mut packets = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if packets.len() == CAPACITY {
+            packets.pop_front();
+        }
+        packets.push_back(packet);
+    }
+
+    /// Oldest packet first
+    pub fn list(&self) -> Vec<Packet> {
+        let packets = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        packets.iter().cloned().collect()
+    }
+}
+
+/// Ids of the registered devices by mac, read again once the storage changed
+#[derive(Default)]
+struct Macs(RwLock<(Option<u64>, HashMap<MacAddress, String>)>);
+
+impl Macs {
+    fn stale(&self) -> bool {
+        let macs = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        macs.0 != Some(Device::generation())
+    }
+
+    /// Reads all device files, blocks until they are read
+    fn refresh(&self) {
+        // Taken before reading, a change during the read is picked up with the next packet
+        let generation = Device::generation();
+        let devices = match Device::check_all() {
+            Ok(files) => files
+                .into_iter()
+                .filter_map(|(path, device)| match device {
+                    Ok(device) => Some((device.mac, device.id.to_string())),
+                    Err(err) => {
+                        warn!(?path, "skip invalid device file: {err}");
+                        None
+                    }
+                })
+                .collect(),
+            Err(err) => {
+                error!("couldn't read devices: {err}");
+                HashMap::new()
+            }
+        };
+        debug!(generation, devices = devices.len(), "read device macs");
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = (Some(generation), devices);
+    }
+
+    fn get(&self, mac: MacAddress) -> Option<String> {
+        let macs = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        macs.1.get(&mac).cloned()
+    }
+}
+
+/// Binds every configured port and the raw socket, then passes each magic packet to `on_packet`.
+///
+/// Returns early if a socket can't be bound, otherwise runs until a receiver fails.
+pub async fn run<F>(config: &Listen, on_packet: F) -> Result<(), Error>
+where
+    F: Fn(Packet) + Clone + Send + Sync + 'static,
+{
+    let mut receivers = JoinSet::new();
+    let macs = Arc::new(Macs::default());
+
+    for port in &config.ports {
+        // A dual-stack socket also receives packets sent to the IPv6 all-nodes group
+        let socket = match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, *port)).await {
+            Ok(socket) => socket,
+            Err(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, *port)).await?,
+        };
+        info!("listen for magic packets on udp/{port}");
+        receivers.spawn(receive_udp(socket, *port, macs.clone(), on_packet.clone()));
+    }
+
+    if config.raw {
+        let protocol = Protocol::from(i32::from(ETHERTYPE_WOL.to_be()));
+        let socket = Socket::new(Domain::PACKET, Type::RAW, Some(protocol))?;
+        info!("listen for magic packets in ethernet frames");
+        let on_packet = on_packet.clone();
+        receivers.spawn_blocking(move || receive_raw(&socket, &macs, &on_packet));
+    }
+
+    while let Some(receiver) = receivers.join_next().await {
+        if let Ok(Err(err)) = receiver {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+async fn receive_udp<F: Fn(Packet)>(
+    socket: UdpSocket,
+    port: u16,
+    macs: Arc<Macs>,
+    on_packet: F,
+) -> Result<(), Error> {
+    let mut buf = [0; 1500];
+    loop {
+        let (len, source) = socket.recv_from(&mut buf).await?;
+        let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+        if macs.stale() {
+            let macs = macs.clone();
+            let _ = tokio::task::spawn_blocking(move || macs.refresh()).await;
+        }
+        handle(
+            &buf[..len],
+            source.to_string(),
+            format!("udp/{port}"),
+            &macs,
+            &on_packet,
+        );
+    }
+}
+
+fn receive_raw<F: Fn(Packet)>(
+    mut socket: &Socket,
+    macs: &Macs,
+    on_packet: &F,
+) -> Result<(), Error> {
+    let mut buf = [0; 1514];
+    loop {
+        let len = socket.read(&mut buf)?;
+        if len < ETHERNET_HEADER {
+            continue;
+        }
+        let source = MacAddress::new([buf[6], buf[7], buf[8], buf[9], buf[10], buf[11]]);
+        if macs.stale() {
+            macs.refresh();
+        }
+        handle(
+            &buf[ETHERNET_HEADER..len],
+            source.to_string(),
+            "raw".to_string(),
+            macs,
+            on_packet,
+        );
+    }
+}
+
+fn handle<F: Fn(Packet)>(
+    payload: &[u8],
+    source: String,
+    transport: String,
+    macs: &Macs,
+    on_packet: &F,
+) {
+    let Some(mac) = parse_magic(payload) else {
+        debug!(source, transport, "received packet is no magic packet");
+        return;
+    };
+
+    let device = macs.get(mac);
+    info!(%mac, ?device, source, transport, "received magic packet");
+
+    on_packet(Packet {
+        received: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs()),
+        source,
+        transport,
+        mac: mac.to_string(),
+        device,
+    });
+}
+
+/// Finds a magic packet in `payload` and returns its target mac
+pub fn parse_magic(payload: &[u8]) -> Option<MacAddress> {
+    payload.windows(MAGIC_LEN).find_map(|window| {
+        let (sync, macs) = window.split_at(6);
+        if sync != [0xff; 6] {
+            return None;
+        }
+        let mac = &macs[..6];
+        macs.chunks_exact(6)
+            .all(|chunk| chunk == mac)
+            .then(|| MacAddress::new([mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]]))
+    })
+}
+
+/// Starts the listener in the background, packets are kept in `packets`
+pub fn spawn(config: Listen, packets: Arc<Packets>) {
+    tokio::spawn(async move {
+        if let Err(err) = run(&config, move |packet| packets.push(packet)).await {
+            error!("packet listener stopped: {err}");
+        }
+    });
+}
@@ -1,135 +1,314 @@
-use crate::config::Config;
-use crate::storage::Device;
-use dashmap::DashMap;
+use crate::config::{Check, Config};
+use crate::error::Error;
+use crate::services::sessions::Sessions;
+use crate::storage::{Device, DeviceId};
 use ipnetwork::IpNetwork;
-use std::{fmt::Display, time::Instant};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr},
+    pin::Pin,
+    time::{Instant, SystemTime},
+};
 use time::Duration;
-use tokio::sync::broadcast::Sender;
-use tracing::{debug, error, trace};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::watch,
+    task::AbortHandle,
+    time::MissedTickBehavior,
+};
+use tracing::{debug, error, trace, warn};
+use utoipa::ToSchema;
 
-pub type StatusMap = DashMap<String, Value>;
+/// Least time between two probe rounds, checks that fail right away don't spin
+const ATTEMPT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How long a TCP connect may take
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Result of a single [`Probe::probe`], errors end the session
+pub type ProbeResult<'a> = Pin<Box<dyn Future<Output = Result<Outcome, Error>> + Send + 'a>>;
+
+/// Answer of the device to one check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Passed, with the round trip time
+    Passed(std::time::Duration),
+    /// Not yet, with the reason, e.g. while the device or service is starting
+    Failed(String),
+}
+
+/// Checks if a device is up, replaceable with a scripted probe in tests
+pub trait Probe: Send + Sync {
+    fn probe(&self, ip: IpAddr, check: Check) -> ProbeResult<'_>;
+}
+
+/// Runs the checks over the network
+pub struct Network;
+
+impl Probe for Network {
+    fn probe(&self, ip: IpAddr, check: Check) -> ProbeResult<'_> {
+        Box::pin(async move {
+            let started = Instant::now();
+            match check {
+                Check::Arp => Ok(if arp(ip).await? {
+                    Outcome::Passed(started.elapsed())
+                } else {
+                    Outcome::Failed("no arp entry".to_string())
+                }),
+                Check::Icmp => match surge_ping::ping(ip, &[0; 8]).await {
+                    Ok((_, duration)) => Ok(Outcome::Passed(duration)),
+                    Err(err @ surge_ping::SurgeError::Timeout { .. }) => {
+                        Ok(Outcome::Failed(err.to_string()))
+                    }
+                    Err(err) => Err(err.into()),
+                },
+                Check::Tcp(port) => {
+                    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((ip, port)))
+                        .await
+                    {
+                        Ok(Ok(_)) => Ok(Outcome::Passed(started.elapsed())),
+                        // Refused or unreachable while the device or service is starting
+                        Ok(Err(err)) => {
+                            trace!(%ip, port, "{err}");
+                            Ok(Outcome::Failed(err.to_string()))
+                        }
+                        Err(_) => Ok(Outcome::Failed("connect timed out".to_string())),
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Whether the device has a complete entry in the ARP cache of the server
+async fn arp(ip: IpAddr) -> Result<bool, Error> {
+    let IpAddr::V4(ip) = ip else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "arp needs an IPv4 address",
+        )
+        .into());
+    };
+
+    // Makes the kernel resolve the address, the entry is there by the next round
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let _ = socket.send_to(&[], (ip, 9)).await;
+
+    let ip = ip.to_string();
+    let table = std::fs::read_to_string("/proc/net/arp")?;
+    Ok(table.lines().skip(1).any(|line| {
+        // IP address, HW type, Flags, HW address, Mask, Device
+        let mut fields = line.split_whitespace();
+        fields.next() == Some(ip.as_str()) && fields.nth(1).is_some_and(|flags| flags != "0x0")
+    }))
+}
 
 #[derive(Debug, Clone)]
 pub struct Value {
+    /// Changed when the device is renamed while the session runs
+    pub device: DeviceId,
     pub ip: IpNetwork,
     pub eta: u64,
-    pub online: bool,
+    /// Current state and progress, status watchers subscribe to get every update
+    pub watch: watch::Sender<Progress>,
+    /// When the magic packet was sent
+    pub started: SystemTime,
+    /// Probe task, aborted when the session is cancelled
+    pub task: Option<AbortHandle>,
 }
 
-pub async fn spawn(
-    tx: Sender<BroadcastCommand>,
-    config: &Config,
-    device: Device,
-    uuid: String,
-    ping_map: &StatusMap,
-) {
-    let timer = Instant::now();
-    let payload = [0; 8];
-
-    let mut msg: Option<BroadcastCommand> = None;
-    while msg.is_none() {
-        // Safe: Only called when ip is set
-        let ip = device.ip.unwrap();
-        let ping = surge_ping::ping(ip.ip(), &payload).await;
-
-        if let Err(ping) = ping {
-            let ping_timeout = matches!(ping, surge_ping::SurgeError::Timeout { .. });
-            if !ping_timeout {
-                error!("{}", ping.to_string());
-                msg = Some(BroadcastCommand::error(uuid.clone()));
-            }
-            if timer.elapsed() >= Duration::minutes(config.pingtimeout) {
-                msg = Some(BroadcastCommand::timeout(uuid.clone()));
-            }
-        } else {
-            let (_, duration) = ping
-                .map_err(|err| error!("{}", err.to_string()))
-                .expect("fatal error");
-            debug!("ping took {:?}", duration);
-            msg = Some(BroadcastCommand::success(uuid.clone()));
-        };
+impl Value {
+    pub fn state(&self) -> SessionState {
+        self.watch.borrow().state
     }
+}
 
-    trace!(?msg);
-
-    let msg = msg.expect("fatal error");
+/// Progress of a session, updated after every probe round
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    pub state: SessionState,
+    /// Seconds since probing started
+    pub elapsed: u64,
+    /// Probe rounds so far, each round runs the checks that weren't reached yet
+    pub attempts: u64,
+    /// Elapsed time in percent of the eta, at most 99 while booting, `None` without boot times
+    pub percent: Option<u64>,
+    /// Error of the check that failed
+    pub error: Option<String>,
+    /// Last check of the latest round that the device didn't pass yet
+    pub failure: Option<Failure>,
+    /// Checks of `pingprobes` that succeeded, in the order they did
+    pub milestones: Vec<Milestone>,
+}
 
-    let _ = tx.send(msg.clone());
-    if msg.command == BroadcastCommands::Success {
-        if timer.elapsed().as_secs() > config.pingthreshold {
-            let newtimes = if let Some(mut oldtimes) = device.times {
-                oldtimes.push(timer.elapsed().as_secs());
-                oldtimes
-            } else {
-                vec![timer.elapsed().as_secs()]
-            };
-
-            let updatedev = Device {
-                id: device.id,
-                mac: device.mac,
-                broadcast_addr: device.broadcast_addr,
-                ip: device.ip,
-                times: Some(newtimes),
-            };
-            updatedev.write().unwrap();
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            state: SessionState::Booting,
+            elapsed: 0,
+            attempts: 0,
+            percent: None,
+            error: None,
+            failure: None,
+            milestones: vec![],
         }
+    }
+}
 
-        ping_map.alter(&uuid, |_, v| Value {
-            ip: v.ip,
-            eta: v.eta,
-            online: true,
-        });
+impl Progress {
+    fn reached(&self, check: Check) -> bool {
+        self.milestones
+            .iter()
+            .any(|milestone| milestone.check == check)
+    }
 
-        tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+    fn update(&mut self, elapsed: u64, eta: u64) {
+        self.elapsed = elapsed;
+        self.percent = elapsed.saturating_mul(100).checked_div(eta).map(|percent| {
+            if self.state == SessionState::Online {
+                100
+            } else {
+                percent.min(99)
+            }
+        });
     }
-    trace!("remove {} from ping_map", uuid);
-    ping_map.remove(&uuid);
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum BroadcastCommands {
-    Success,
-    Timeout,
-    Error,
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Milestone {
+    pub check: Check,
+    /// Seconds since probing started
+    pub elapsed: u64,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct BroadcastCommand {
-    pub uuid: String,
-    pub command: BroadcastCommands,
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failure {
+    pub check: Check,
+    pub reason: String,
 }
 
-impl Display for BroadcastCommand {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let prefix = match self.command {
-            BroadcastCommands::Success => "start",
-            BroadcastCommands::Timeout => "timeout",
-            BroadcastCommands::Error => "error",
-        };
+/// Sessions are kept for `pingretention` seconds after they left `Booting`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionState {
+    Booting,
+    Online,
+    Timeout,
+    Error,
+    /// Only seen by status watchers, cancelled sessions are removed right away
+    Cancelled,
+}
 
-        f.write_str(format!("{prefix}_{}", self.uuid).as_str())
+impl SessionState {
+    /// Final `<kind>_<uuid>` message for status watchers, `None` while booting
+    pub fn message(self, uuid: &str) -> Option<String> {
+        let kind = match self {
+            Self::Booting => return None,
+            Self::Online => "start",
+            Self::Timeout => "timeout",
+            Self::Error => "error",
+            Self::Cancelled => "cancelled",
+        };
+        Some(format!("{kind}_{uuid}"))
     }
 }
 
-impl BroadcastCommand {
-    pub fn success(uuid: String) -> Self {
-        Self {
-            uuid,
-            command: BroadcastCommands::Success,
+pub async fn spawn(
+    config: &Config,
+    id: DeviceId,
+    ip: IpAddr,
+    eta: u64,
+    uuid: String,
+    sessions: &Sessions,
+    probe: &dyn Probe,
+) {
+    let timer = Instant::now();
+    let mut progress = Progress::default();
+    let mut rounds = tokio::time::interval(ATTEMPT);
+    rounds.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let state = 'probe: loop {
+        rounds.tick().await;
+        progress.attempts += 1;
+        progress.failure = None;
+        for &check in &config.pingprobes {
+            if progress.reached(check) {
+                continue;
+            }
+            match probe.probe(ip, check).await {
+                Ok(Outcome::Passed(duration)) => {
+                    debug!(%check, "probe took {:?}", duration);
+                    progress.milestones.push(Milestone {
+                        check,
+                        elapsed: timer.elapsed().as_secs(),
+                    });
+                }
+                Ok(Outcome::Failed(reason)) => {
+                    trace!(%check, reason, "check not passed yet");
+                    progress.failure = Some(Failure { check, reason });
+                }
+                Err(err) => {
+                    error!(%check, "{err}");
+                    progress.error = Some(err.to_string());
+                    break 'probe SessionState::Error;
+                }
+            }
         }
-    }
 
-    pub fn timeout(uuid: String) -> Self {
-        Self {
-            uuid,
-            command: BroadcastCommands::Timeout,
+        if progress.milestones.len() == config.pingprobes.len() {
+            break SessionState::Online;
+        }
+        if timer.elapsed() >= Duration::minutes(config.pingtimeout) {
+            break SessionState::Timeout;
+        }
+        progress.update(timer.elapsed().as_secs(), eta);
+        sessions.progress(&uuid, progress.clone());
+    };
+
+    trace!(?state);
+
+    progress.state = state;
+    progress.update(timer.elapsed().as_secs(), eta);
+    sessions.finish(&uuid, progress);
+    if state == SessionState::Online && timer.elapsed().as_secs() > config.pingthreshold {
+        // The device may have been edited or renamed while it booted
+        let id = sessions.get(&uuid).map_or(id, |session| session.device);
+        if let Err(err) = add_time(&id, timer.elapsed().as_secs()) {
+            warn!(?id, "couldn't save boot time: {err}");
         }
     }
 
-    pub fn error(uuid: String) -> Self {
-        Self {
-            uuid,
-            command: BroadcastCommands::Error,
+    tokio::time::sleep(tokio::time::Duration::from_secs(config.pingretention)).await;
+    trace!("remove session {}", uuid);
+    sessions.remove(&uuid);
+}
+
+/// Stops the probe of a session and removes it, watchers of a booting session see `Cancelled`.
+/// Returns `false` if there is no session with this uuid.
+pub fn cancel(sessions: &Sessions, uuid: &str) -> bool {
+    let Some(session) = sessions.remove(uuid) else {
+        return false;
+    };
+    debug!(uuid, device = %session.device, "cancelled ping session");
+    session.watch.send_if_modified(|progress| {
+        let booting = progress.state == SessionState::Booting;
+        if booting {
+            progress.state = SessionState::Cancelled;
         }
+        booting
+    });
+    true
+}
+
+fn add_time(id: &DeviceId, time: u64) -> Result<(), crate::error::Error> {
+    let _lock = Device::lock();
+    let device = Device::read(id)?;
+    let mut times = device.times.unwrap_or_default();
+    times.push(time);
+    Device {
+        times: Some(times),
+        ..device
     }
+    .write()
 }
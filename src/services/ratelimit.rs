@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tracing::{debug, trace};
+
+use crate::{config::Limit, error::Error};
+
+/// Windows are only cleaned up once there are more than this many keys
+const CLEANUP_AT: usize = 1024;
+
+/// Fixed window counters, keyed by client ip or device id
+#[derive(Default)]
+pub struct RateLimiter {
+    windows: DashMap<String, Window>,
+}
+
+struct Window {
+    start: Instant,
+    length: Duration,
+    requests: u32,
+}
+
+impl RateLimiter {
+    /// Counts a request for `key`, fails with [`Error::RateLimited`] if `limit` is exceeded
+    pub fn check(&self, key: &str, limit: Limit) -> Result<(), Error> {
+        let now = Instant::now();
+        let length = Duration::from_secs(limit.seconds);
+        if self.windows.len() > CLEANUP_AT {
+            self.windows
+                .retain(|_, window| now.duration_since(window.start) < window.length);
+        }
+
+        let mut window = self.windows.entry(key.to_string()).or_insert(Window {
+            start: now,
+            length,
+            requests: 0,
+        });
+        // A changed limit starts a new window
+        if now.duration_since(window.start) >= window.length || window.length != length {
+            *window = Window {
+                start: now,
+                length,
+                requests: 0,
+            };
+        }
+
+        if window.requests >= limit.requests {
+            let remaining = window
+                .length
+                .saturating_sub(now.duration_since(window.start));
+            debug!(key, ?remaining, "rate limited");
+            return Err(Error::RateLimited {
+                // Rounded up, a retry right at the end of the window would still be limited
+                retry_after: remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0),
+            });
+        }
+        window.requests += 1;
+        trace!(key, requests = window.requests, "counted request");
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use crate::config::{self, Config};
+use crate::error::Error;
+use crate::storage::{Device, DeviceId};
+use crate::AppState;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::BTreeSet,
+    env,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc::unbounded_channel;
+use tracing::{debug, error, info, level_filters::LevelFilter, trace, warn};
+use tracing_subscriber::{reload::Handle, EnvFilter, Registry};
+
+pub type LogHandle = Handle<EnvFilter, Registry>;
+
+/// Builds the log filter, `RUST_LOG` takes precedence over the configured `loglevel`
+pub fn log_filter(loglevel: &str) -> EnvFilter {
+    let directives = env::var(EnvFilter::DEFAULT_ENV).unwrap_or_else(|_| loglevel.to_string());
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse_lossy(directives)
+}
+
+/// Loads the config from disk and swaps it into the running state.
+///
+/// An invalid config is rejected and the previous one is kept.
+pub fn reload(state: &AppState) -> Result<(), Error> {
+    let config = Config::load().map_err(|err| {
+        error!("reload rejected, keeping previous config: {err}");
+        err
+    })?;
+
+    let current = state.config.load();
+    if current.listeners() != config.listeners() {
+        warn!("listeners changed, a restart is needed to apply them");
+    }
+    if current.listen != config.listen {
+        warn!("packet listener changed, a restart is needed to apply it");
+    }
+    if current.tls.is_some() != config.tls.is_some() {
+        warn!("tls enabled or disabled, a restart is needed to apply it");
+    }
+    reload_tls(state, &config);
+    state.agents.reload(&config.agents);
+
+    if let Err(err) = state
+        .log_handle
+        .modify(|filter| *filter = log_filter(&config.loglevel))
+    {
+        error!("couldn't apply loglevel: {err}");
+    }
+
+    debug!(?config, "new config");
+    state.config.store(Arc::new(config));
+    info!("config reloaded");
+
+    Ok(())
+}
+
+/// Reloads the certificates, a failed reload keeps serving the previous ones
+fn reload_tls(state: &AppState, config: &Config) {
+    if let (Some(tls), Some(tls_config)) = (&state.tls, &config.tls) {
+        if let Err(err) = tls.reload(tls_config) {
+            error!("tls reload rejected, keeping previous certificates: {err}");
+        }
+    }
+}
+
+/// Watches the config files, the device storage and the certificates and reloads on change
+pub fn spawn(state: Arc<AppState>) -> notify::Result<()> {
+    let (tx, mut rx) = unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    watcher.watch(Path::new("."), RecursiveMode::NonRecursive)?;
+    watcher.watch(Device::storage(), RecursiveMode::NonRecursive)?;
+    // Directories are watched instead of the files, certificate renewals usually replace them
+    let cert_dirs = tls_files(&state.config.load())
+        .iter()
+        .filter_map(|file| file.parent().map(Path::to_path_buf))
+        .collect::<BTreeSet<_>>();
+    for dir in cert_dirs {
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    }
+
+    tokio::spawn(async move {
+        // Dropping the watcher stops it, so it has to live as long as the task
+        let _watcher = watcher;
+        while let Some(event) = rx.recv().await {
+            let mut events = vec![event];
+            // Editors tend to emit several events per save, wait for them to settle
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            while let Ok(event) = rx.try_recv() {
+                events.push(event);
+            }
+
+            let mut paths = BTreeSet::new();
+            for event in events {
+                match event {
+                    Ok(event) if is_change(&event) => paths.extend(event.paths),
+                    Ok(_) => {}
+                    Err(err) => error!("file watcher: {err}"),
+                }
+            }
+
+            let tls_files = tls_files(&state.config.load());
+            let changes = handle(&paths, &tls_files);
+            if changes.config {
+                let _ = reload(&state);
+            } else if changes.tls {
+                reload_tls(&state, &state.config.load());
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn is_change(event: &Event) -> bool {
+    trace!(?event, "file event");
+    matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    )
+}
+
+/// Absolute paths of the configured certificate files
+fn tls_files(config: &Config) -> Vec<PathBuf> {
+    let Some(tls) = &config.tls else {
+        return vec![];
+    };
+    [Some(&tls.cert), Some(&tls.key), tls.clientca.as_ref()]
+        .into_iter()
+        .flatten()
+        .filter_map(|file| std::path::absolute(file).ok())
+        .collect()
+}
+
+#[derive(Default)]
+struct Changes {
+    config: bool,
+    tls: bool,
+}
+
+/// Handles the changed files and reports what needs to be reloaded
+fn handle(paths: &BTreeSet<PathBuf>, tls_files: &[PathBuf]) -> Changes {
+    let mut changes = Changes::default();
+    for path in paths {
+        if std::path::absolute(path).is_ok_and(|path| tls_files.contains(&path)) {
+            info!(?path, "certificate changed");
+            changes.tls = true;
+            continue;
+        }
+
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        let in_storage = path.parent().is_some_and(|dir| {
+            std::path::absolute(dir).ok() == std::path::absolute(Device::storage()).ok()
+        });
+
+        if in_storage {
+            Device::changed();
+            let Some(id) = name.strip_suffix(".json") else {
+                continue;
+            };
+            if !path.exists() {
+                info!(?id, "device removed from storage");
+                continue;
+            }
+            match DeviceId::new(id).and_then(|id| Device::read(&id)) {
+                Ok(_) => info!(?id, "device changed on disk"),
+                Err(err) => warn!(?id, "changed device file is invalid: {err}"),
+            }
+        } else if config::FILES.contains(&name) {
+            info!(?name, "config file changed");
+            changes.config = true;
+        }
+    }
+
+    changes
+}
@@ -0,0 +1,146 @@
+use std::time::SystemTime;
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use ipnetwork::IpNetwork;
+use tokio::{sync::watch, task::AbortHandle};
+use tracing::{debug, trace};
+use uuid::Uuid;
+
+use crate::services::ping::{Progress, SessionState, Value};
+use crate::storage::DeviceId;
+
+/// Ping sessions by uuid, with at most one booting session and probe task per device.
+///
+/// `devices` is always locked before `sessions` and no guard of `sessions` is held while
+/// `devices` is locked, so both maps can be used from the probe tasks and handlers at once.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: DashMap<String, Value>,
+    /// Uuid of the booting session of each device
+    devices: DashMap<DeviceId, String>,
+}
+
+/// Result of [`Sessions::join_or_start`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Joined {
+    /// The device was already booting, no probe task has to be started
+    Running { uuid: String, eta: u64 },
+    /// A new session was created, its probe task has to be set with [`Sessions::set_task`]
+    Started { uuid: String },
+}
+
+impl Sessions {
+    /// Joins the booting session of `id` or creates a new one, atomically per device
+    pub fn join_or_start(&self, id: &DeviceId, ip: IpNetwork, eta: u64) -> Joined {
+        match self.devices.entry(id.clone()) {
+            Entry::Occupied(running) => {
+                let uuid = running.get().clone();
+                let eta = self.sessions.get(&uuid).map_or(eta, |session| session.eta);
+                trace!(uuid, "join booting session of {id}");
+                Joined::Running { uuid, eta }
+            }
+            Entry::Vacant(vacant) => {
+                let uuid = Uuid::new_v4().to_string();
+                self.sessions.insert(
+                    uuid.clone(),
+                    Value {
+                        device: id.clone(),
+                        ip,
+                        eta,
+                        watch: watch::Sender::new(Progress::default()),
+                        started: SystemTime::now(),
+                        task: None,
+                    },
+                );
+                vacant.insert(uuid.clone());
+                debug!(uuid, "start ping session for {id}");
+                Joined::Started { uuid }
+            }
+        }
+    }
+
+    /// Sets the probe task of a booting session, aborts it if the session was cancelled already
+    pub fn set_task(&self, uuid: &str, task: AbortHandle) {
+        match self.sessions.get_mut(uuid) {
+            Some(mut session) if session.state() == SessionState::Booting => {
+                session.task = Some(task);
+            }
+            Some(_) => {}
+            None => task.abort(),
+        }
+    }
+
+    /// Uuid and eta of the booting session of `id`
+    pub fn booting(&self, id: &DeviceId) -> Option<(String, u64)> {
+        let uuid = self.devices.get(id)?.clone();
+        let eta = self.sessions.get(&uuid)?.eta;
+        Some((uuid, eta))
+    }
+
+    pub fn get(&self, uuid: &str) -> Option<Value> {
+        self.sessions.get(uuid).map(|session| session.clone())
+    }
+
+    pub fn contains(&self, uuid: &str) -> bool {
+        self.sessions.contains_key(uuid)
+    }
+
+    /// All sessions with their uuid
+    pub fn list(&self) -> Vec<(String, Value)> {
+        self.sessions
+            .iter()
+            .map(|session| (session.key().clone(), session.value().clone()))
+            .collect()
+    }
+
+    /// Publishes the progress of a booting session
+    pub fn progress(&self, uuid: &str, progress: Progress) {
+        if let Some(session) = self.sessions.get(uuid) {
+            session.watch.send_if_modified(|current| {
+                let booting = current.state == SessionState::Booting;
+                if booting {
+                    *current = progress;
+                }
+                booting
+            });
+        }
+    }
+
+    /// Ends a booting session with the final progress, the next start of the device creates a new one
+    pub fn finish(&self, uuid: &str, progress: Progress) {
+        let state = progress.state;
+        let Some(id) = self.get(uuid).map(|session| session.device) else {
+            return;
+        };
+        self.devices.remove_if(&id, |_, booting| booting == uuid);
+        if let Some(mut session) = self.sessions.get_mut(uuid) {
+            session.watch.send_replace(progress);
+            session.task = None;
+        }
+        debug!(uuid, ?state, "finished ping session of {id}");
+    }
+
+    /// Removes the session and stops its probe task, returns it if it existed
+    pub fn remove(&self, uuid: &str) -> Option<Value> {
+        let id = self.get(uuid)?.device;
+        self.devices.remove_if(&id, |_, booting| booting == uuid);
+        let (_, session) = self.sessions.remove(uuid)?;
+        if let Some(task) = &session.task {
+            task.abort();
+        }
+        Some(session)
+    }
+
+    /// Moves the sessions of a renamed device to its new id
+    pub fn rename(&self, from: &DeviceId, to: &DeviceId) {
+        if let Some((_, uuid)) = self.devices.remove(from) {
+            self.devices.insert(to.clone(), uuid);
+        }
+        for mut session in self.sessions.iter_mut() {
+            if session.device == *from {
+                debug!(uuid = session.key(), "move ping session to {to}");
+                session.device = to.clone();
+            }
+        }
+    }
+}
@@ -1,91 +1,436 @@
 use std::{
     ffi::OsStr,
-    fs::{create_dir_all, File, read_dir},
-    io::{Read, Write},
-    path::Path,
+    fs::{self, create_dir_all, read_dir, File},
+    io::{ErrorKind, Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, OnceLock, PoisonError,
+    },
 };
 
 use ipnetwork::IpNetwork;
 use mac_address::MacAddress;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use tracing::{debug, trace, warn};
+use serde_json::{json, Value};
+use tracing::{debug, info, trace, warn};
 use utoipa::ToSchema;
 
 use crate::error::Error;
+use crate::wol::{ALL_NODES, DEFAULT_PORT};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+mod id;
+mod migrate;
+
+pub use id::DeviceId;
+pub use migrate::VERSION;
+
+/// See [`Device::lock`]
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// See [`Device::generation`]
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// See [`Device::storage`]
+static STORAGE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Result of reading a single device file, see [`Device::check_all`]
+pub type FileCheck = (PathBuf, Result<Device, Error>);
+
+/// Device file whose name isn't a valid [`DeviceId`], with a suggested id to rename it to
+pub type InvalidFile = (PathBuf, Option<DeviceId>);
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
 pub struct Device {
-    pub id: String,
+    #[schema(value_type = String, pattern = "^[A-Za-z0-9_-][A-Za-z0-9._-]{0,63}$", example = "desktop")]
+    pub id: DeviceId,
+    #[schema(value_type = String, pattern = "^([0-9A-F]{2}:){5}[0-9A-F]{2}$", example = "12:34:56:AB:CD:EF")]
     pub mac: MacAddress,
-    pub broadcast_addr: String,
+    /// Derived from `ip` if not set, see [`Device::broadcast`]
+    #[schema(value_type = Option<String>, example = "10.0.1.255:9")]
+    pub broadcast_addr: Option<SocketAddr>,
+    /// With prefix, so the broadcast address can be derived from it
+    #[schema(value_type = Option<String>, example = "10.0.1.47/24")]
     pub ip: Option<IpNetwork>,
+    /// Seconds the device needed to boot, for the ping eta
     pub times: Option<Vec<u64>>,
+    /// Name of the relay agent that sends the magic packet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+    /// Interface IPv6 packets are sent on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(flatten)]
+    pub meta: Metadata,
+}
+
+/// Descriptive fields for humans, not used to wake the device
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, Default)]
+pub struct Metadata {
+    /// Shown instead of the id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// Icon name or url, interpreted by the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
 }
 
 impl Device {
-    const STORAGE_PATH: &'static str = "devices";
+    /// Default of [`Device::storage`], relative to the working directory
+    pub const STORAGE_PATH: &'static str = "devices";
+
+    /// Directory the device files are stored in
+    pub fn storage() -> &'static Path {
+        STORAGE.get_or_init(|| PathBuf::from(Self::STORAGE_PATH))
+    }
+
+    /// Stores devices in `path` instead of [`Device::STORAGE_PATH`], only possible before the
+    /// storage is first used. Returns `path` if the storage was already set.
+    pub fn set_storage(path: impl Into<PathBuf>) -> Result<(), PathBuf> {
+        STORAGE.set(path.into())
+    }
+
+    /// Changes with every write or removal of a device file, caches of the storage compare it
+    pub fn generation() -> u64 {
+        GENERATION.load(Ordering::Acquire)
+    }
+
+    /// Marks the storage as changed, e.g. when a file was edited by hand
+    pub fn changed() {
+        GENERATION.fetch_add(1, Ordering::AcqRel);
+    }
 
     pub fn setup() -> Result<String, Error> {
-        trace!("check for storage STORAGE_PATH=\"{}\"", Self::STORAGE_PATH);
-        let sp = Path::new(Self::STORAGE_PATH);
+        let sp = Self::storage();
+        trace!(?sp, "check for storage");
         if !sp.exists() {
             warn!("device storage path doesn't exist, creating it");
-            create_dir_all(Self::STORAGE_PATH)?;
+            create_dir_all(sp)?;
         };
 
-        debug!("device storage STORAGE_PATH=\"{}\"", Self::STORAGE_PATH);
+        debug!(?sp, "device storage");
+
+        Self::migrate()?;
 
-        Ok(Self::STORAGE_PATH.to_string())
+        for (path, suggestion) in Self::invalid_files()? {
+            match suggestion {
+                Some(id) => warn!(
+                    ?path,
+                    "device file has an invalid name and is ignored, rename it to {id}.json and set its id to \"{id}\""
+                ),
+                None => warn!(?path, "device file has an invalid name and is ignored"),
+            }
+        }
+
+        Ok(sp.display().to_string())
+    }
+
+    /// Address the magic packet is sent to.
+    ///
+    /// Without a set `broadcast_addr` the directed broadcast of the ip's subnet is used, or the
+    /// all-nodes multicast group for IPv6.
+    pub fn broadcast(&self) -> Result<SocketAddr, Error> {
+        if let Some(addr) = self.broadcast_addr {
+            return Ok(addr);
+        }
+        match self.ip {
+            Some(IpNetwork::V4(net)) if net.prefix() < 32 => {
+                Ok(SocketAddr::new(net.broadcast().into(), DEFAULT_PORT))
+            }
+            Some(IpNetwork::V6(_)) => Ok(SocketAddr::new(ALL_NODES.into(), DEFAULT_PORT)),
+            _ => Err(Error::NoBroadcast),
+        }
+    }
+
+    /// Strong entity tag of the stored representation, for `ETag` and `If-Match`
+    pub fn etag(&self) -> String {
+        // FNV-1a, stable across restarts unlike the std hasher
+        let hash = json!(self)
+            .to_string()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+        format!("\"{hash:016x}\"")
+    }
+
+    /// `display_name` if set, the id otherwise
+    pub fn name(&self) -> &str {
+        self.meta.display_name.as_deref().unwrap_or(&self.id)
+    }
+
+    fn path(id: &DeviceId) -> PathBuf {
+        Self::storage().join(format!("{id}.json"))
+    }
+
+    pub fn exists(id: &DeviceId) -> bool {
+        Self::path(id).exists()
     }
 
-    pub fn read(id: &str) -> Result<Self, Error> {
+    pub fn read(id: &DeviceId) -> Result<Self, Error> {
         trace!(?id, "attempt to read file");
-        let mut file = File::open(format!("{}/{id}.json", Self::STORAGE_PATH))?;
+        let mut file = File::open(Self::path(id)).map_err(|err| {
+            if err.kind() == ErrorKind::NotFound {
+                Error::DeviceNotFound { id: id.to_string() }
+            } else {
+                err.into()
+            }
+        })?;
         let mut buf = String::new();
         file.read_to_string(&mut buf)?;
         trace!(?id, ?buf, "read successfully from file");
 
-        let dev = serde_json::from_str(&buf)?;
-        Ok(dev)
+        Self::from_stored(id, serde_json::from_str(&buf)?)
+    }
+
+    /// Reads a stored device written by this or an older version of webol
+    pub fn from_stored(id: &str, mut device: Value) -> Result<Self, Error> {
+        migrate::upgrade(id, &mut device)?;
+        // The file name is authoritative, see `Device::rename`
+        if let Some(device) = device.as_object_mut() {
+            device.insert("id".to_string(), json!(id));
+        }
+        Ok(serde_json::from_value(device)?)
     }
 
     pub fn read_all() -> Result<Vec<Self>, Error> {
         trace!("attempt to read all files");
-        let st_path = read_dir(Self::STORAGE_PATH)?;
+        let st_path = read_dir(Self::storage())?;
 
         let mut devices = vec![];
         for file_path in st_path {
             let file_path = file_path?;
-            if file_path.path().extension() != Some(OsStr::new("json")) { continue; };
-            let mut file = File::open(file_path.path())?;
+            if file_path.path().extension() != Some(OsStr::new("json")) {
+                continue;
+            };
+            if !has_valid_name(&file_path.path()) {
+                trace!(?file_path, "skip file with invalid name");
+                continue;
+            }
+            let path = file_path.path();
+            let mut file = File::open(&path)?;
             let mut buf = String::new();
             file.read_to_string(&mut buf)?;
             trace!(?file, ?buf, "read successfully from file");
-            devices.push(serde_json::from_str(&buf)?);
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            // Like `check_all`, one broken file doesn't hide the other devices
+            match serde_json::from_str(&buf)
+                .map_err(Error::from)
+                .and_then(|device| Self::from_stored(&name, device))
+            {
+                Ok(device) => devices.push(device),
+                Err(err) => warn!(?path, "skip invalid device file: {err}"),
+            }
         }
 
         Ok(devices)
     }
 
+    /// Reads every device file on its own, so one broken file doesn't hide the others
+    pub fn check_all() -> Result<Vec<FileCheck>, Error> {
+        trace!("attempt to check all files");
+        let mut results = vec![];
+        for file_path in read_dir(Self::storage())? {
+            let path = file_path?.path();
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            };
+            let device = File::open(&path)
+                .and_then(|mut file| {
+                    let mut buf = String::new();
+                    file.read_to_string(&mut buf)?;
+                    Ok(buf)
+                })
+                .map_err(Error::from)
+                .and_then(|buf| {
+                    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                    Self::from_stored(&name, serde_json::from_str(&buf)?)
+                });
+            results.push((path, device));
+        }
+        results.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Ok(results)
+    }
+
+    /// Lists device files that can't be addressed because their name isn't a valid id
+    pub fn invalid_files() -> Result<Vec<InvalidFile>, Error> {
+        let mut files = vec![];
+        for file_path in read_dir(Self::storage())? {
+            let path = file_path?.path();
+            if path.extension() != Some(OsStr::new("json")) || has_valid_name(&path) {
+                continue;
+            }
+            let suggestion = path
+                .file_stem()
+                .and_then(|stem| DeviceId::sanitize(&stem.to_string_lossy()))
+                .filter(|id| !Self::exists(id));
+            files.push((path, suggestion));
+        }
+        files.sort();
+
+        Ok(files)
+    }
+
+    /// Serializes read-modify-write cycles on device files, hold it from reading a device until
+    /// the changed device is written
+    pub fn lock() -> MutexGuard<'static, ()> {
+        LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replaces the file, readers see either the old or the new device
     pub fn write(&self) -> Result<(), Error> {
         trace!(?self.id, ?self, "attempt to write to file");
-        let mut file = File::create(format!("{}/{}.json", Self::STORAGE_PATH, self.id))?;
-        file.write_all(json!(self).to_string().as_bytes())?;
+        let mut device = json!(self);
+        device["version"] = json!(VERSION);
+        write_file(&Self::path(&self.id), &device)?;
         trace!(?self.id, "wrote successfully to file");
 
         Ok(())
     }
+
+    /// Writes every device to a temporary file before any stored file is replaced, a failed write
+    /// leaves the storage unchanged
+    pub fn write_all(devices: &[Self]) -> Result<(), Error> {
+        let mut staged = vec![];
+        for device in devices {
+            let path = Self::path(&device.id);
+            let mut value = json!(device);
+            value["version"] = json!(VERSION);
+            match write_tmp(&path, &value) {
+                Ok(tmp) => staged.push((tmp, path)),
+                Err(err) => {
+                    for (tmp, _) in &staged {
+                        let _ = fs::remove_file(tmp);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        for (tmp, path) in staged {
+            fs::rename(tmp, path)?;
+        }
+        Self::changed();
+        Ok(())
+    }
+
+    /// Upgrades files written by older versions in place, the original is kept next to it as
+    /// `<id>.json.v<version>.bak`.
+    ///
+    /// Fails if a file was written by a newer webol, files that can't be upgraded are reported and
+    /// left as they are.
+    fn migrate() -> Result<(), Error> {
+        let _lock = Self::lock();
+        for file_path in read_dir(Self::storage())? {
+            let path = file_path?.path();
+            if path.extension() != Some(OsStr::new("json")) || !has_valid_name(&path) {
+                continue;
+            }
+            let id = path.file_stem().unwrap_or_default().to_string_lossy();
+            let Ok(mut device) = serde_json::from_str::<Value>(&fs::read_to_string(&path)?) else {
+                warn!(?path, "device file isn't valid json and can't be upgraded");
+                continue;
+            };
+            let version = match migrate::upgrade(&id, &mut device) {
+                Ok(version) => version,
+                Err(err @ Error::DeviceVersion { .. }) => return Err(err),
+                Err(err) => {
+                    warn!(?path, "{err}");
+                    continue;
+                }
+            };
+            if version == VERSION {
+                continue;
+            }
+
+            let backup = path.with_extension(format!("json.v{version}.bak"));
+            fs::copy(&path, &backup)?;
+            write_file(&path, &device)?;
+            info!(
+                ?path,
+                ?backup,
+                from = version,
+                to = VERSION,
+                "upgraded device file"
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn delete(id: &DeviceId) -> Result<(), Error> {
+        trace!(?id, "attempt to delete file");
+        fs::remove_file(Self::path(id)).map_err(|err| {
+            if err.kind() == ErrorKind::NotFound {
+                Error::DeviceNotFound { id: id.to_string() }
+            } else {
+                err.into()
+            }
+        })?;
+        Self::changed();
+        Ok(())
+    }
+
+    /// Moves the device to `new_id`, keeping everything else including its ping times.
+    ///
+    /// The file is moved in one step and rewritten with the new id afterwards, so there is never
+    /// a second file of the device. Has to be called with [`Device::lock`] held.
+    pub fn rename(id: &DeviceId, new_id: DeviceId) -> Result<Self, Error> {
+        let device = Self {
+            id: new_id,
+            ..Self::read(id)?
+        };
+        let path = Self::path(&device.id);
+        // `fs::rename` replaces an existing file, which the lock keeps from appearing until the move
+        if path.exists() {
+            return Err(Error::DeviceExists {
+                id: device.id.to_string(),
+            });
+        }
+        fs::rename(Self::path(id), &path)?;
+        Self::changed();
+        device.write()?;
+        debug!(?id, new_id = ?device.id, "renamed device");
+
+        Ok(device)
+    }
+}
+
+/// Writes to a temporary file first and renames it, so the file is never half written
+fn write_file(path: &Path, device: &Value) -> Result<(), Error> {
+    let tmp = write_tmp(path, device)?;
+    fs::rename(&tmp, path)?;
+    Device::changed();
+
+    Ok(())
+}
+
+/// Writes `device` next to `path`, returns the temporary file that replaces it when renamed
+fn write_tmp(path: &Path, device: &Value) -> Result<PathBuf, Error> {
+    let tmp = path.with_extension("json.tmp");
+    let written = File::create(&tmp).and_then(|mut file| {
+        file.write_all(device.to_string().as_bytes())?;
+        file.sync_all()
+    });
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(err.into());
+    }
+    Ok(tmp)
 }
 
-// Dead Code allowed because of use in OpenApi Macro (not really dead code)
-#[allow(dead_code)]
-#[derive(ToSchema)]
-#[schema(as = Device)]
-pub struct DeviceSchema {
-    pub id: String,
-    pub mac: String,
-    pub broadcast_addr: String,
-    pub ip: String,
-    pub times: Option<Vec<i64>>,
+fn has_valid_name(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(OsStr::to_str)
+        .is_some_and(|stem| DeviceId::new(stem).is_ok())
 }
@@ -0,0 +1,89 @@
+use std::{fmt, ops::Deref, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Longest accepted id, enough for hostnames while keeping file names short
+pub const MAX_LEN: usize = 64;
+
+/// Id of a device, also the name of its file in the storage.
+///
+/// Only ASCII letters, digits, `-`, `_` and `.` are allowed and the id can't start with a `.`,
+/// so it never points outside of [`Device::storage`](super::Device::storage).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DeviceId(String);
+
+impl DeviceId {
+    pub fn new(id: impl Into<String>) -> Result<Self, Error> {
+        let id = id.into();
+        let reason = if id.is_empty() {
+            "can't be empty"
+        } else if id.len() > MAX_LEN {
+            "is longer than 64 characters"
+        } else if id.starts_with('.') {
+            "can't start with a dot"
+        } else if !id.chars().all(is_allowed) {
+            "may only contain letters, digits, '-', '_' and '.'"
+        } else {
+            return Ok(Self(id));
+        };
+        Err(Error::InvalidId { id, reason })
+    }
+
+    /// Replaces disallowed characters with `-`, for ids derived from hostnames or file names
+    pub fn sanitize(id: &str) -> Option<Self> {
+        let id = id
+            .trim_start_matches('.')
+            .chars()
+            .map(|c| if is_allowed(c) { c } else { '-' })
+            .take(MAX_LEN)
+            .collect::<String>();
+        Self::new(id).ok()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+const fn is_allowed(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')
+}
+
+impl Deref for DeviceId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for DeviceId {
+    type Err = Error;
+
+    fn from_str(id: &str) -> Result<Self, Error> {
+        Self::new(id)
+    }
+}
+
+impl TryFrom<String> for DeviceId {
+    type Error = Error;
+
+    fn try_from(id: String) -> Result<Self, Error> {
+        Self::new(id)
+    }
+}
+
+impl From<DeviceId> for String {
+    fn from(id: DeviceId) -> Self {
+        id.0
+    }
+}
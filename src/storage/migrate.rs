@@ -0,0 +1,72 @@
+use std::net::{IpAddr, SocketAddr};
+
+use serde_json::{json, Map, Value};
+
+use crate::error::Error;
+use crate::wol::DEFAULT_PORT;
+
+/// Version of the device files written by this webol
+pub const VERSION: u32 = 1;
+
+/// Changes a stored device from one version to the next
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// `MIGRATIONS[n]` upgrades a device from version `n` to `n + 1`
+const MIGRATIONS: [Migration; VERSION as usize] = [v0];
+
+/// Version a stored device was written in, files without a `version` field are version 0
+fn version(device: &Map<String, Value>) -> Option<u32> {
+    match device.get("version") {
+        None => Some(0),
+        Some(version) => version.as_u64().and_then(|v| u32::try_from(v).ok()),
+    }
+}
+
+/// Upgrades a stored device to [`VERSION`] and returns the version it was written in
+pub fn upgrade(id: &str, device: &mut Value) -> Result<u32, Error> {
+    let failed = |version, message: &str| Error::Migration {
+        id: id.to_string(),
+        version,
+        message: message.to_string(),
+    };
+
+    let device = device
+        .as_object_mut()
+        .ok_or_else(|| failed(0, "not a json object"))?;
+    let stored = version(device).ok_or_else(|| failed(0, "version isn't a number"))?;
+    if stored > VERSION {
+        return Err(Error::DeviceVersion {
+            id: id.to_string(),
+            version: stored,
+        });
+    }
+
+    for (from, migration) in (stored..).zip(&MIGRATIONS[stored as usize..]) {
+        migration(device).map_err(|message| failed(from, &message))?;
+        device.insert("version".to_string(), json!(from + 1));
+    }
+
+    Ok(stored)
+}
+
+/// `broadcast_addr` was a required string, later optional and derived from the ip
+fn v0(device: &mut Map<String, Value>) -> Result<(), String> {
+    let addr = match device.remove("broadcast_addr") {
+        None | Some(Value::Null) => Value::Null,
+        Some(Value::String(addr)) if addr.trim().is_empty() => Value::Null,
+        Some(Value::String(addr)) => {
+            let addr = addr.trim();
+            if let Ok(addr) = addr.parse::<SocketAddr>() {
+                json!(addr)
+            } else if let Ok(ip) = addr.parse::<IpAddr>() {
+                json!(SocketAddr::new(ip, DEFAULT_PORT))
+            } else {
+                return Err(format!("broadcast_addr \"{addr}\" isn't an ip address"));
+            }
+        }
+        Some(_) => return Err("broadcast_addr isn't a string".to_string()),
+    };
+    device.insert("broadcast_addr".to_string(), addr);
+
+    Ok(())
+}
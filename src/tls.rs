@@ -0,0 +1,88 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use arc_swap::ArcSwap;
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{debug, info};
+
+use crate::{config, error::Error};
+
+/// Request extension, set when the client presented a certificate signed by `tls.clientca`
+#[derive(Debug, Clone)]
+pub struct ClientCert;
+
+/// Server side TLS config, swappable so renewed certificates apply to new connections
+pub struct Tls {
+    config: ArcSwap<ServerConfig>,
+}
+
+impl Tls {
+    pub fn load(config: &config::Tls) -> Result<Self, Error> {
+        Ok(Self {
+            config: ArcSwap::from_pointee(server_config(config)?),
+        })
+    }
+
+    pub fn reload(&self, config: &config::Tls) -> Result<(), Error> {
+        self.config.store(Arc::new(server_config(config)?));
+        info!("tls certificates reloaded");
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.load_full())
+    }
+}
+
+fn server_config(config: &config::Tls) -> Result<ServerConfig, Error> {
+    debug!(?config.cert, ?config.key, "load tls certificates");
+    let certs = read_certs(&config.cert)?;
+    let key = read_key(&config.key)?;
+
+    let builder = ServerConfig::builder();
+    let builder = if let Some(clientca) = &config.clientca {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(clientca)? {
+            roots.add(cert)?;
+        }
+        // Clients without a certificate are still accepted, `auth::auth` decides if that's enough
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+            .allow_unauthenticated()
+            .build()
+            .map_err(|err| Error::Tls {
+                source: tokio_rustls::rustls::Error::General(err.to_string()),
+            })?;
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(File::open(Path::new(path))?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(Error::NoPemItem {
+            path: path.to_string(),
+        });
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(File::open(Path::new(path))?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| Error::NoPemItem {
+        path: path.to_string(),
+    })
+}
@@ -0,0 +1,145 @@
+//! Signed single-use wake tokens, see `POST /start/{id}/token`
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use ring::{
+    hmac::{self, HMAC_SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use tracing::{debug, trace};
+
+use crate::{config::Wake, error::Error, hex, storage::DeviceId};
+
+/// Tokens are `<expires>.<nonce>.<signature>.<id>`, the id is last as it may contain dots
+const PARTS: usize = 4;
+
+/// Issues and redeems wake tokens, redeemed ones are remembered until they expire
+pub struct Tokens {
+    /// Signs tokens if `wake.secret` isn't configured
+    fallback: [u8; 32],
+    /// Nonces of redeemed tokens with their expiry
+    used: DashMap<String, u64>,
+}
+
+/// A validly signed, unexpired token
+#[derive(Debug)]
+pub struct Token {
+    pub id: DeviceId,
+    /// Unix timestamp in seconds
+    pub expires: u64,
+    nonce: String,
+}
+
+impl Default for Tokens {
+    /// # Panics
+    ///
+    /// Panics if the system has no source of randomness for the fallback key
+    fn default() -> Self {
+        let mut fallback = [0; 32];
+        SystemRandom::new()
+            .fill(&mut fallback)
+            .expect("system randomness");
+        Self {
+            fallback,
+            used: DashMap::new(),
+        }
+    }
+}
+
+impl Tokens {
+    /// Token for starting `id` once within `wake.tokenttl` seconds, returned with its expiry
+    pub fn issue(&self, config: &Wake, id: &DeviceId) -> Result<(String, u64), Error> {
+        let mut nonce = [0; 16];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| invalid("no randomness"))?;
+        let token = Token {
+            id: id.clone(),
+            expires: now() + config.tokenttl,
+            nonce: hex::encode(&nonce),
+        };
+        debug!(%id, expires = token.expires, "issued wake token");
+        Ok((self.sign(config, &token), token.expires))
+    }
+
+    /// Checks the signature and expiry without using up the token
+    pub fn check(&self, config: &Wake, token: &str) -> Result<Token, Error> {
+        let parts = token.splitn(PARTS, '.').collect::<Vec<_>>();
+        let [expires, nonce, signature, id] = parts[..] else {
+            return Err(invalid("malformed"));
+        };
+        let signature = hex::decode(signature).ok_or_else(|| invalid("malformed"))?;
+        hmac::verify(
+            &self.key(config),
+            message(expires, nonce, id).as_bytes(),
+            &signature,
+        )
+        .map_err(|_| invalid("invalid signature"))?;
+
+        let expires = expires.parse().map_err(|_| invalid("malformed"))?;
+        if expires <= now() {
+            return Err(invalid("expired"));
+        }
+        if self.used.contains_key(nonce) {
+            return Err(invalid("already used"));
+        }
+        Ok(Token {
+            id: DeviceId::new(id)?,
+            expires,
+            nonce: nonce.to_string(),
+        })
+    }
+
+    /// Checks the token and marks it as used, a token can only be redeemed once
+    pub fn redeem(&self, config: &Wake, token: &str) -> Result<Token, Error> {
+        let token = self.check(config, token)?;
+
+        let now = now();
+        self.used.retain(|_, expires| *expires > now);
+        if self
+            .used
+            .insert(token.nonce.clone(), token.expires)
+            .is_some()
+        {
+            return Err(invalid("already used"));
+        }
+        trace!(id = %token.id, "redeemed wake token");
+        Ok(token)
+    }
+
+    /// Serialized token, signed with the current key
+    fn sign(&self, config: &Wake, token: &Token) -> String {
+        let expires = token.expires.to_string();
+        let message = message(&expires, &token.nonce, &token.id);
+        let signature = hmac::sign(&self.key(config), message.as_bytes());
+        format!(
+            "{expires}.{}.{}.{}",
+            token.nonce,
+            hex::encode(signature.as_ref()),
+            token.id
+        )
+    }
+
+    fn key(&self, config: &Wake) -> hmac::Key {
+        let secret = config
+            .secret
+            .as_ref()
+            .map_or(&self.fallback[..], String::as_bytes);
+        hmac::Key::new(HMAC_SHA256, secret)
+    }
+}
+
+fn message(expires: &str, nonce: &str, id: &str) -> String {
+    format!("wake.{expires}.{nonce}.{id}")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+fn invalid(reason: &'static str) -> Error {
+    Error::InvalidToken { reason }
+}
@@ -1,27 +1,36 @@
-use std::net::{ToSocketAddrs, UdpSocket};
+use std::{
+    net::{Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+};
 
+use mac_address::MacAddress;
+use nix::net::if_::if_nametoindex;
 use tracing::trace;
 
 use crate::error::Error;
 
-/// Creates the magic packet from a mac address
+/// Port magic packets are sent to if the device doesn't set one
+pub const DEFAULT_PORT: u16 = 9;
+
+/// IPv6 has no broadcast, the link-local all-nodes group is used instead
+pub const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// Creates the magic packet from a mac address written like `AA:BB:CC:DD:EE:FF`
 ///
-/// # Panics
+/// # Errors
 ///
-/// Panics if `mac_addr` is an invalid mac
+/// Fails if `mac_addr` isn't a mac address
 pub fn create_buffer(mac_addr: &str) -> Result<Vec<u8>, Error> {
-    let mut mac = Vec::new();
-    let sp = mac_addr.split(':');
-    for f in sp {
-        mac.push(u8::from_str_radix(f, 16)?);
-    }
+    Ok(magic_packet(MacAddress::from_str(mac_addr)?))
+}
+
+/// Creates the magic packet, six `0xff` followed by the mac 16 times
+pub fn magic_packet(mac: MacAddress) -> Vec<u8> {
     let mut buf = vec![255; 6];
     for _ in 0..16 {
-        for i in &mac {
-            buf.push(*i);
-        }
+        buf.extend_from_slice(&mac.bytes());
     }
-    Ok(buf)
+    buf
 }
 
 /// Sends a buffer on UDP broadcast
@@ -32,6 +41,51 @@ pub fn send_packet<A: ToSocketAddrs>(
 ) -> Result<usize, Error> {
     let socket = UdpSocket::bind(bind_addr)?;
     socket.set_broadcast(true)?;
-    trace!(?buffer ,"start with");
+    trace!(?buffer, "start with");
     Ok(socket.send_to(buffer, broadcast_addr)?)
 }
+
+/// Sends a buffer to `target`, IPv6 targets are sent out on `interface`
+pub fn send_to(target: SocketAddr, interface: Option<&str>, buffer: &[u8]) -> Result<usize, Error> {
+    match target {
+        SocketAddr::V4(_) => send_packet("0.0.0.0:0".parse()?, target, buffer),
+        SocketAddr::V6(mut target) => {
+            if let Some(interface) = interface {
+                target.set_scope_id(interface_index(interface)?);
+            }
+            let socket = UdpSocket::bind("[::]:0")?;
+            trace!(?buffer, ?target, "start with");
+            Ok(socket.send_to(buffer, target)?)
+        }
+    }
+}
+
+/// Sends magic packets, replaceable to record them instead in tests
+pub trait PacketSender: Send + Sync {
+    fn send(
+        &self,
+        target: SocketAddr,
+        interface: Option<&str>,
+        buffer: &[u8],
+    ) -> Result<usize, Error>;
+}
+
+/// Sends magic packets on the network with [`send_to`]
+pub struct Udp;
+
+impl PacketSender for Udp {
+    fn send(
+        &self,
+        target: SocketAddr,
+        interface: Option<&str>,
+        buffer: &[u8],
+    ) -> Result<usize, Error> {
+        send_to(target, interface, buffer)
+    }
+}
+
+pub fn interface_index(interface: &str) -> Result<u32, Error> {
+    if_nametoindex(interface).map_err(|_| Error::UnknownInterface {
+        interface: interface.to_string(),
+    })
+}
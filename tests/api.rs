@@ -0,0 +1,365 @@
+mod common;
+
+use std::fs;
+
+use common::{device, Harness, SECRET};
+use serde_json::{json, Value};
+use webol::storage::Device;
+
+#[tokio::test]
+async fn device_crud() {
+    let harness = Harness::start(false).await;
+    let client = &harness.client;
+
+    let response = client
+        .put(harness.url("/device"))
+        .json(&device("crud", 10))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 201);
+    assert_eq!(response.headers()["location"], "/device/crud");
+    let etag = response.headers()["etag"].clone();
+    let created: Value = response.json().await.unwrap();
+    assert_eq!(created["mac"], "AA:BB:CC:DD:EE:0A");
+    // Derived from the ip when the device is started
+    assert_eq!(created["broadcast_addr"], Value::Null);
+
+    let response = client
+        .put(harness.url("/device"))
+        .json(&device("crud", 10))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 409);
+
+    let response = client
+        .get(harness.url("/device/crud"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["etag"], etag);
+    assert_eq!(response.json::<Value>().await.unwrap(), created);
+
+    let response = client
+        .put(harness.url("/device/crud"))
+        .header("if-match", etag.clone())
+        .json(&json!({ "mac": "AA:BB:CC:DD:EE:0B", "ip": "10.0.0.11/24", "owner": "ana" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let updated: Value = response.json().await.unwrap();
+    assert_eq!(updated["mac"], "AA:BB:CC:DD:EE:0B");
+    assert_eq!(updated["owner"], "ana");
+
+    // The etag changed with the update
+    let response = client
+        .patch(harness.url("/device/crud"))
+        .header("if-match", etag)
+        .header("content-type", "application/merge-patch+json")
+        .body(r#"{"owner": null}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 412);
+
+    let response = client
+        .patch(harness.url("/device/crud"))
+        .header("content-type", "application/merge-patch+json")
+        .body(r#"{"owner": null, "location": "office"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let patched: Value = response.json().await.unwrap();
+    assert_eq!(patched["owner"], Value::Null);
+    assert_eq!(patched["location"], "office");
+
+    // An existing device isn't replaced
+    harness.create(device("crud-taken", 12)).await;
+    let response = client
+        .post(harness.url("/device/crud/rename"))
+        .json(&json!({ "id": "crud-taken" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 409);
+    let response = client
+        .get(harness.url("/device/crud-taken"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        response.json::<Value>().await.unwrap()["mac"],
+        "AA:BB:CC:DD:EE:0C"
+    );
+
+    let response = client
+        .post(harness.url("/device/crud/rename"))
+        .json(&json!({ "id": "crud-renamed" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let response = client
+        .get(harness.url("/device/crud"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    let response = client
+        .get(harness.url("/device/crud-renamed"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        response.json::<Value>().await.unwrap()["location"],
+        "office"
+    );
+}
+
+#[tokio::test]
+async fn invalid_devices_are_rejected() {
+    let harness = Harness::start(false).await;
+    let client = &harness.client;
+
+    let response = client
+        .put(harness.url("/device"))
+        .json(&json!({ "id": "invalid", "mac": "nope", "ip": "10.0.0.300/24" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 422);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+    let problem: Value = response.json().await.unwrap();
+    let fields = problem["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["field"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(fields, ["mac", "ip"]);
+
+    let response = client
+        .get(harness.url("/device/..%2Fconfig"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let response = client
+        .get(harness.url("/device/missing"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    assert_eq!(
+        response.json::<Value>().await.unwrap(),
+        json!({ "error": "Requested device not found" })
+    );
+}
+
+#[tokio::test]
+async fn invalid_payloads_are_problems() {
+    let harness = Harness::start(false).await;
+    let client = &harness.client;
+
+    for path in ["/discover/import", "/relay"] {
+        let response = client
+            .post(harness.url(path))
+            .header("content-type", "application/json")
+            .body("not json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400, "{path}");
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/problem+json"
+        );
+
+        let response = client
+            .post(harness.url(path))
+            .json(&json!({ "mac": 1 }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 422, "{path}");
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/problem+json"
+        );
+
+        let response = client
+            .post(harness.url(path))
+            .body("[]")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 415, "{path}");
+        let problem: Value = response.json().await.unwrap();
+        assert_eq!(problem["status"], 415);
+    }
+}
+
+#[tokio::test]
+async fn relay_rejects_invalid_addresses_by_field() {
+    let harness = Harness::start(false).await;
+
+    let response = harness
+        .client
+        .post(harness.url("/relay"))
+        .json(&json!({ "mac": "nope", "broadcast_addr": "10.0.0.255" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 422);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+    let problem: Value = response.json().await.unwrap();
+    let fields = problem["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["field"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(fields, ["mac", "broadcast_addr"]);
+    assert!(harness.sent.packets().is_empty());
+}
+
+#[tokio::test]
+async fn devices_are_listed_and_filtered() {
+    let harness = Harness::start(false).await;
+    for (id, host, tag) in [
+        ("list-a", 20, "list"),
+        ("list-b", 21, "list"),
+        ("list-c", 22, "other"),
+    ] {
+        let mut device = device(id, host);
+        device["tags"] = json!([tag]);
+        harness.create(device).await;
+    }
+
+    let response = harness
+        .client
+        .get(harness.url("/devices?tag=list&order=desc"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-total-count"], "2");
+    let devices: Vec<Value> = response.json().await.unwrap();
+    let ids = devices
+        .iter()
+        .map(|device| device["id"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, ["list-b", "list-a"]);
+}
+
+#[tokio::test]
+async fn corrupt_device_files_are_skipped() {
+    let harness = Harness::start(false).await;
+    harness.create(device("beside-corrupt", 23)).await;
+    fs::write(Device::storage().join("corrupt.json"), "{ not json").unwrap();
+
+    let response = harness
+        .client
+        .get(harness.url("/devices"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let devices: Vec<Value> = response.json().await.unwrap();
+    assert!(devices
+        .iter()
+        .any(|device| device["id"] == "beside-corrupt"));
+    assert!(devices.iter().all(|device| device["id"] != "corrupt"));
+}
+
+#[tokio::test]
+async fn discover_rejects_large_networks_to_ping() {
+    let harness = Harness::start(false).await;
+
+    let response = harness
+        .client
+        .post(harness.url("/discover"))
+        .json(&json!({ "cidr": "10.0.0.0/16", "ping": true }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn discover_pings_with_the_probe() {
+    let harness = Harness::start(false).await;
+
+    let response = harness
+        .client
+        .post(harness.url("/discover"))
+        .json(&json!({ "cidr": "10.9.0.0/29", "ping": true }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(harness.probe.concurrent("10.9.0.0"), 0);
+    for host in 1..7 {
+        assert_eq!(harness.probe.concurrent(&format!("10.9.0.{host}")), 1);
+    }
+    assert_eq!(harness.probe.concurrent("10.9.0.7"), 0);
+
+    // Interface networks too large to ping are skipped
+    let response = harness
+        .client
+        .post(harness.url("/discover"))
+        .json(&json!({ "ping": true }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn auth_is_required() {
+    let harness = Harness::start(true).await;
+    harness.create(device("auth", 30)).await;
+    let client = &harness.client;
+
+    let response = client
+        .get(harness.url("/device/auth"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+
+    let response = client
+        .get(harness.url("/device/auth"))
+        .header("authorization", "wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+
+    let response = client
+        .post(harness.url("/start/auth"))
+        .header("authorization", "wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+    assert!(harness.sent.packets().is_empty());
+
+    let response = client
+        .get(harness.url("/device/auth"))
+        .header("authorization", SECRET)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
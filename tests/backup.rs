@@ -0,0 +1,57 @@
+mod common;
+
+use std::fs;
+
+use common::{device, Harness, SECRET};
+use webol::storage::Device;
+
+/// Restoring deletes every device not in the archive, so this binary has a single test
+#[tokio::test]
+async fn backup_skips_and_restore_removes_corrupt_files() {
+    let harness = Harness::start(true).await;
+    harness.create(device("kept", 90)).await;
+    let corrupt = Device::storage().join("corrupt.json");
+    fs::write(&corrupt, "{ not json").unwrap();
+
+    let response = harness
+        .client
+        .post(harness.url("/admin/backup"))
+        .header("authorization", SECRET)
+        .header("x-backup-passphrase", "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let archive = response.bytes().await.unwrap();
+
+    let tampered = String::from_utf8_lossy(&archive)
+        .replacen(":210000:", ":1:", 1)
+        .into_bytes();
+    let response = harness
+        .client
+        .post(harness.url("/admin/restore?config=false"))
+        .header("authorization", SECRET)
+        .header("x-backup-passphrase", "secret")
+        .body(tampered)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(corrupt.exists());
+
+    let response = harness
+        .client
+        .post(harness.url("/admin/restore?config=false"))
+        .header("authorization", SECRET)
+        .header("x-backup-passphrase", "secret")
+        .body(archive)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let restored: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(restored["devices"], 1);
+    assert_eq!(restored["deleted"], 1);
+    assert!(!corrupt.exists());
+    assert!(Device::storage().join("kept.json").exists());
+}
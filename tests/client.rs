@@ -0,0 +1,144 @@
+mod common;
+
+use common::{client_tls, device, Harness, Reply, SECRET};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+use webol::{
+    client::{DPayload, Error, Status},
+    storage::Metadata,
+    Client, DeviceId,
+};
+
+fn payload(id: &str, host: u8) -> DPayload {
+    DPayload {
+        id: id.to_string(),
+        mac: format!("AA:BB:CC:DD:EE:{host:02X}"),
+        broadcast_addr: None,
+        ip: Some(format!("10.0.0.{host}/24")),
+        via: None,
+        interface: None,
+        meta: Metadata::default(),
+    }
+}
+
+#[tokio::test]
+async fn client_manages_and_starts_devices() {
+    let harness = Harness::start(true).await;
+    let client = Client::new(harness.url("/")).with_secret(SECRET);
+    let id = DeviceId::new("client").unwrap();
+
+    let created = client.create(&payload("client", 50)).await.unwrap();
+    assert_eq!(created.id, id);
+    assert_eq!(created.mac.to_string(), "AA:BB:CC:DD:EE:32");
+
+    let mut changed = payload("", 51);
+    changed.meta.owner = Some("ana".to_string());
+    let updated = client.update(&id, &changed).await.unwrap();
+    assert_eq!(updated.meta.owner.as_deref(), Some("ana"));
+    assert_eq!(client.device(&id).await.unwrap().mac, updated.mac);
+    assert!(client
+        .devices()
+        .await
+        .unwrap()
+        .iter()
+        .any(|device| device.id == id));
+
+    harness.probe.set("10.0.0.51", Reply::Online);
+    let started = client.start(&id, true).await.unwrap();
+    assert!(started.boot);
+    assert_eq!(harness.sent.packets().len(), 1);
+
+    let mut status = client.status(&started.uuid.unwrap()).await.unwrap();
+    assert_eq!(status.next().await.unwrap(), Some(Status::Eta(0)));
+    assert_eq!(status.next().await.unwrap(), Some(Status::Online));
+}
+
+#[tokio::test]
+async fn client_reports_server_errors() {
+    let harness = Harness::start(true).await;
+    let missing = DeviceId::new("client-missing").unwrap();
+
+    let unauthorized = Client::new(harness.url("")).device(&missing).await;
+    assert!(matches!(
+        unauthorized,
+        Err(Error::Server { status: 401, .. })
+    ));
+
+    let client = Client::new(harness.url("")).with_secret(SECRET);
+    match client.device(&missing).await {
+        Err(Error::Server { status, message }) => {
+            assert_eq!(status, 404);
+            assert_eq!(message, "Requested device not found");
+        }
+        other => panic!("expected not found, got {other:?}"),
+    }
+
+    client
+        .create(&payload("client-conflict", 52))
+        .await
+        .unwrap();
+    match client.create(&payload("client-conflict", 52)).await {
+        Err(Error::Server { status: 409, .. }) => {}
+        other => panic!("expected a conflict, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn client_follows_status_over_tls() {
+    let harness = Harness::start_tls(true).await;
+    let client = Client::new(harness.url(""))
+        .with_secret(SECRET)
+        .with_tls(client_tls())
+        .unwrap();
+    let id = DeviceId::new("client-tls").unwrap();
+    harness.create(device("client-tls", 53)).await;
+
+    harness.probe.set("10.0.0.53", Reply::Online);
+    let started = client.start(&id, true).await.unwrap();
+    let mut status = client.status(&started.uuid.unwrap()).await.unwrap();
+    assert_eq!(status.next().await.unwrap(), Some(Status::Eta(0)));
+    assert_eq!(status.next().await.unwrap(), Some(Status::Online));
+}
+
+#[test]
+fn magic_packets_are_created_from_mac_strings() {
+    let packet = webol::create_buffer("AA:BB:CC:DD:EE:FF").unwrap();
+    assert_eq!(
+        packet,
+        common::magic_packet([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff])
+    );
+    assert!(webol::create_buffer("AA:BB:CC:DD:EE").is_err());
+    assert!(webol::create_buffer("not a mac").is_err());
+}
+
+#[tokio::test]
+async fn status_stream_reports_error_close_frames() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        socket.next().await;
+        let frame = CloseFrame {
+            code: CloseCode::Error,
+            reason: "status socket failed".into(),
+        };
+        socket.send(Message::Close(Some(frame))).await.unwrap();
+    });
+
+    let mut status = Client::new(format!("http://{addr}"))
+        .status("closed")
+        .await
+        .unwrap();
+    match status.next().await {
+        Err(Error::Closed { code, reason }) => {
+            assert_eq!(code, 1011);
+            assert_eq!(reason, "status socket failed");
+        }
+        other => panic!("expected a close error, got {other:?}"),
+    }
+}
@@ -0,0 +1,379 @@
+//! In-process webol server with a temporary device storage and a fake network
+
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use webol::{
+    auth::Methods,
+    config::{self, Auth, Check, Config, Listener, RateLimit, Wake},
+    error::Error,
+    router, server,
+    services::{
+        ping::{Outcome, Probe, ProbeResult},
+        reload,
+    },
+    storage::Device,
+    tls::Tls,
+    wol::PacketSender,
+    AppState,
+};
+
+pub const SECRET: &str = "test-secret";
+
+/// Harnesses alive in this binary, the storage is removed with the last one
+static HARNESSES: Mutex<usize> = Mutex::new(0);
+
+/// Device files of all tests in this binary, tests use their own ids to not interfere
+fn storage() -> &'static PathBuf {
+    static STORAGE: OnceLock<PathBuf> = OnceLock::new();
+    STORAGE.get_or_init(|| {
+        let dir = env::temp_dir().join(format!("webol-test-{}", process::id()));
+        Device::set_storage(&dir).expect("storage set before the harness");
+        dir
+    })
+}
+
+/// Creates the storage if this is the only harness alive
+fn acquire_storage() {
+    let mut harnesses = HARNESSES.lock().unwrap();
+    if *harnesses == 0 {
+        storage();
+        Device::setup().expect("storage can be created");
+    }
+    *harnesses += 1;
+}
+
+/// Magic packet recorded instead of being sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub target: SocketAddr,
+    pub interface: Option<String>,
+    pub buffer: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct Recorder(Mutex<Vec<Packet>>);
+
+impl Recorder {
+    pub fn packets(&self) -> Vec<Packet> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl PacketSender for Recorder {
+    fn send(
+        &self,
+        target: SocketAddr,
+        interface: Option<&str>,
+        buffer: &[u8],
+    ) -> Result<usize, Error> {
+        self.0.lock().unwrap().push(Packet {
+            target,
+            interface: interface.map(String::from),
+            buffer: buffer.to_vec(),
+        });
+        Ok(buffer.len())
+    }
+}
+
+/// What the [`FakeProbe`] answers for an ip and check, unknown ones are offline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    Online,
+    Offline,
+    Error,
+}
+
+#[derive(Default)]
+pub struct FakeProbe {
+    replies: Mutex<HashMap<IpAddr, Reply>>,
+    /// Replies for single checks, take precedence over `replies`
+    checks: Mutex<HashMap<(IpAddr, Check), Reply>>,
+    /// Probes running right now and the most that ran at once, per ip
+    running: Arc<Mutex<HashMap<IpAddr, (usize, usize)>>>,
+}
+
+impl FakeProbe {
+    pub fn set(&self, ip: &str, reply: Reply) {
+        self.replies
+            .lock()
+            .unwrap()
+            .insert(ip.parse().unwrap(), reply);
+    }
+
+    pub fn set_check(&self, ip: &str, check: Check, reply: Reply) {
+        self.checks
+            .lock()
+            .unwrap()
+            .insert((ip.parse().unwrap(), check), reply);
+    }
+
+    /// Most probes of `ip` that ran at the same time, more than one means duplicate probe tasks
+    pub fn concurrent(&self, ip: &str) -> usize {
+        let ip = ip.parse().unwrap();
+        self.running
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .map_or(0, |(_, max)| *max)
+    }
+}
+
+impl Probe for FakeProbe {
+    fn probe(&self, ip: IpAddr, check: Check) -> ProbeResult<'_> {
+        let reply = self
+            .checks
+            .lock()
+            .unwrap()
+            .get(&(ip, check))
+            .or(self.replies.lock().unwrap().get(&ip))
+            .copied();
+        let running = self.running.clone();
+        {
+            let mut running = running.lock().unwrap();
+            let (now, max) = running.entry(ip).or_default();
+            *now += 1;
+            *max = (*max).max(*now);
+        }
+        Box::pin(async move {
+            let result = match reply.unwrap_or(Reply::Offline) {
+                Reply::Online => Ok(Outcome::Passed(Duration::from_millis(1))),
+                Reply::Offline => {
+                    // Like a ping that isn't answered, without waiting for its timeout
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    Ok(Outcome::Failed("offline".to_string()))
+                }
+                Reply::Error => Err(Error::Io {
+                    source: std::io::Error::other("probe failed"),
+                }),
+            };
+            if let Some((now, _)) = running.lock().unwrap().get_mut(&ip) {
+                *now -= 1;
+            }
+            result
+        })
+    }
+}
+
+/// `tests/fixtures/{name}`
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Trusts the CA of the TLS harness certificate, valid for `127.0.0.1`
+pub fn client_tls() -> ClientConfig {
+    let pem = std::fs::read(fixture("ca.pem")).unwrap();
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+pub struct Harness {
+    pub addr: SocketAddr,
+    /// Served over TLS, see [`Harness::start_tls`]
+    pub tls: bool,
+    pub client: reqwest::Client,
+    pub sent: Arc<Recorder>,
+    pub probe: Arc<FakeProbe>,
+    pub state: Arc<AppState>,
+}
+
+impl Harness {
+    /// Serves all routes including `/admin`, `auth` requires [`SECRET`] as `Authorization`
+    pub async fn start(auth: bool) -> Self {
+        Self::start_with(auth, |_| {}).await
+    }
+
+    /// Like [`Harness::start`], with changes to the default test config
+    pub async fn start_with(auth: bool, configure: impl FnOnce(&mut Config)) -> Self {
+        let (state, sent, probe) = Self::state(auth, configure);
+        let state = Arc::new(state);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(&state, true, auth);
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Self {
+            addr,
+            tls: false,
+            client: reqwest::Client::new(),
+            sent,
+            probe,
+            state,
+        }
+    }
+
+    /// Like [`Harness::start`], served over HTTPS by [`server::serve`] with the certificate in
+    /// `tests/fixtures`, clients have to trust [`client_tls`]
+    pub async fn start_tls(auth: bool) -> Self {
+        let (state, sent, probe) = Self::state(auth, |_| {});
+        let tls = Tls::load(&config::Tls {
+            cert: fixture("cert.pem"),
+            key: fixture("key.pem"),
+            clientca: None,
+            redirect: None,
+        })
+        .unwrap();
+        let state = Arc::new(state.with_tls(Some(tls)));
+
+        // `serve` binds itself, the port is only free until then
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = Listener {
+            addr: addr.to_string(),
+            admin: true,
+            auth,
+            tls: true,
+            mode: None,
+        };
+        let app = router(&state, true, auth);
+        tokio::spawn(server::serve(listener, app, state.clone()));
+        while TcpStream::connect(addr).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        Self {
+            addr,
+            tls: true,
+            client: reqwest::Client::builder()
+                .use_preconfigured_tls(client_tls())
+                .build()
+                .unwrap(),
+            sent,
+            probe,
+            state,
+        }
+    }
+
+    fn state(
+        auth: bool,
+        configure: impl FnOnce(&mut Config),
+    ) -> (AppState, Arc<Recorder>, Arc<FakeProbe>) {
+        acquire_storage();
+
+        let mut config = Config {
+            serveraddr: "127.0.0.1:0".to_string(),
+            pingtimeout: 1,
+            pingthreshold: 1,
+            pingretention: 60,
+            pingprobes: vec![Check::Icmp],
+            loglevel: "info".to_string(),
+            auth: Auth {
+                method: if auth { Methods::Key } else { Methods::None },
+                secret: SECRET.to_string(),
+            },
+            tls: None,
+            listeners: vec![],
+            relay: true,
+            agents: vec![],
+            ouifile: None,
+            listen: None,
+            ratelimit: RateLimit::default(),
+            wake: Wake::default(),
+        };
+        configure(&mut config);
+        let (_, log_handle) = tracing_subscriber::reload::Layer::new(reload::log_filter("info"));
+
+        let sent = Arc::new(Recorder::default());
+        let probe = Arc::new(FakeProbe::default());
+        let state = AppState::new(config, log_handle)
+            .with_sender(sent.clone())
+            .with_probe(probe.clone());
+        (state, sent, probe)
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{scheme}://{}{path}", self.addr)
+    }
+
+    pub fn ws_url(&self, path: &str) -> String {
+        let scheme = if self.tls { "wss" } else { "ws" };
+        format!("{scheme}://{}{path}", self.addr)
+    }
+
+    /// Registers a device and returns it as answered by the server
+    pub async fn create(&self, device: Value) -> Value {
+        let response = self
+            .client
+            .put(self.url("/device"))
+            .header("authorization", SECRET)
+            .json(&device)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 201, "{device}");
+        response.json().await.unwrap()
+    }
+
+    /// Starts the device and returns the answer of `/start/{id}`
+    pub async fn start_device(&self, id: &str, ping: bool) -> Value {
+        let response = self
+            .client
+            .post(self.url(&format!("/start/{id}")))
+            .json(&json!({ "ping": ping }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        response.json().await.unwrap()
+    }
+
+    /// Starts the device with ping and returns the uuid of its session
+    pub async fn ping_device(&self, id: &str) -> String {
+        let response = self.start_device(id, true).await;
+        response["uuid"]
+            .as_str()
+            .expect("uuid for ping")
+            .to_string()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let mut harnesses = HARNESSES.lock().unwrap();
+        *harnesses -= 1;
+        if *harnesses == 0 {
+            let _ = std::fs::remove_dir_all(storage());
+        }
+    }
+}
+
+/// Device in the test network 10.0.0.0/24
+pub fn device(id: &str, host: u8) -> Value {
+    json!({
+        "id": id,
+        "mac": format!("AA:BB:CC:DD:EE:{host:02X}"),
+        "ip": format!("10.0.0.{host}/24"),
+    })
+}
+
+/// Magic packet for `mac`, six `0xff` followed by the mac 16 times
+pub fn magic_packet(mac: [u8; 6]) -> Vec<u8> {
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+    packet
+}
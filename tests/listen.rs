@@ -0,0 +1,62 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{device, magic_packet, Harness};
+use tokio::{net::UdpSocket, sync::mpsc, time::timeout};
+use webol::{
+    config::Listen,
+    services::listen::{self, Packet},
+};
+
+#[tokio::test]
+async fn received_packets_name_devices_registered_later() {
+    let harness = Harness::start(false).await;
+    let port = {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.local_addr().unwrap().port()
+    };
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let config = Listen {
+        ports: vec![port],
+        raw: false,
+    };
+    tokio::spawn(async move {
+        listen::run(&config, move |packet| {
+            let _ = tx.send(packet);
+        })
+        .await
+    });
+
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let packet = receive(&sender, port, &mut rx).await;
+    assert_eq!(packet.mac, "AA:BB:CC:DD:EE:50");
+    assert_eq!(packet.device, None);
+
+    harness.create(device("listened", 80)).await;
+    timeout(Duration::from_secs(5), async {
+        // Packets sent before the device was registered may still be queued
+        while receive(&sender, port, &mut rx).await.device.is_none() {}
+    })
+    .await
+    .expect("device found in time");
+}
+
+/// Sends magic packets for `AA:BB:CC:DD:EE:50` until the listener reports one
+async fn receive(
+    sender: &UdpSocket,
+    port: u16,
+    rx: &mut mpsc::UnboundedReceiver<Packet>,
+) -> Packet {
+    let packet = magic_packet([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 80]);
+    timeout(Duration::from_secs(5), async {
+        loop {
+            sender.send_to(&packet, ("127.0.0.1", port)).await.unwrap();
+            if let Ok(Some(packet)) = timeout(Duration::from_millis(100), rx.recv()).await {
+                return packet;
+            }
+        }
+    })
+    .await
+    .expect("packet received in time")
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use std::{env, fs};
+
+use common::Harness;
+use serde_json::Value;
+use utoipa::OpenApi;
+use webol::ApiDoc;
+
+const SNAPSHOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+/// Fails when the served spec changes, review the diff and update the snapshot with
+/// `UPDATE_OPENAPI=1 cargo test`
+#[tokio::test]
+async fn openapi_snapshot() {
+    let harness = Harness::start(false).await;
+    let spec: Value = harness
+        .client
+        .get(harness.url("/api-docs/openapi.json"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    if env::var_os("UPDATE_OPENAPI").is_some() {
+        // Written from the spec itself, `Value` would sort the keys
+        fs::write(SNAPSHOT, ApiDoc::openapi().to_pretty_json().unwrap() + "\n").unwrap();
+    }
+    let snapshot: Value = serde_json::from_str(&fs::read_to_string(SNAPSHOT).unwrap()).unwrap();
+    assert!(
+        spec == snapshot,
+        "served OpenAPI spec differs from openapi.json, run `UPDATE_OPENAPI=1 cargo test` to update it"
+    );
+}
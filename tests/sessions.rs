@@ -0,0 +1,359 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{device, Harness, Reply};
+use serde_json::{json, Value};
+use tokio::time::{sleep, timeout};
+use webol::{
+    client::{Progress, SessionState, Status, StatusStream},
+    config::Check,
+    Client, DeviceId,
+};
+
+/// Reads progress messages until one matches
+async fn progress_until(status: &mut StatusStream, done: impl Fn(&Progress) -> bool) -> Progress {
+    timeout(Duration::from_secs(10), async {
+        loop {
+            match status.next().await.unwrap() {
+                Some(Status::Progress(progress)) if done(&progress) => return progress,
+                Some(Status::Progress(_)) => {}
+                other => panic!("expected progress, got {other:?}"),
+            }
+        }
+    })
+    .await
+    .expect("progress in time")
+}
+
+#[tokio::test]
+async fn sessions_are_listed_until_cancelled() {
+    let harness = Harness::start(false).await;
+    harness.create(device("session", 60)).await;
+    let uuid = harness.ping_device("session").await;
+
+    let sessions: Vec<Value> = harness
+        .client
+        .get(harness.url("/sessions"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let session = sessions
+        .iter()
+        .find(|session| session["uuid"] == uuid.as_str())
+        .expect("session is listed");
+    assert_eq!(session["device"], "session");
+    assert_eq!(session["ip"], "10.0.0.60/24");
+    assert_eq!(session["state"], "booting");
+    assert_eq!(session["eta"], 0);
+
+    let client = Client::new(harness.url(""));
+    let mut status = client.status(&uuid).await.unwrap();
+    assert_eq!(status.next().await.unwrap(), Some(Status::Eta(0)));
+
+    client.cancel(&uuid).await.unwrap();
+    let cancelled = timeout(Duration::from_secs(5), status.next())
+        .await
+        .unwrap();
+    assert_eq!(cancelled.unwrap(), Some(Status::Cancelled));
+
+    let response = harness
+        .client
+        .get(harness.url(&format!("/sessions/{uuid}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    let response = harness
+        .client
+        .delete(harness.url(&format!("/sessions/{uuid}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    // The probe task was stopped, the device coming online changes nothing
+    harness.probe.set("10.0.0.60", Reply::Online);
+    sleep(Duration::from_millis(50)).await;
+    assert!(client
+        .sessions()
+        .await
+        .unwrap()
+        .iter()
+        .all(|session| session.uuid != uuid));
+}
+
+#[tokio::test]
+async fn finished_sessions_are_kept() {
+    let harness = Harness::start(false).await;
+    harness.create(device("session-online", 61)).await;
+    harness.probe.set("10.0.0.61", Reply::Online);
+    let client = Client::new(harness.url(""));
+
+    let started = client
+        .start(&DeviceId::new("session-online").unwrap(), true)
+        .await
+        .unwrap();
+    let uuid = started.uuid.unwrap();
+    let mut status = client.status(&uuid).await.unwrap();
+    status.next().await.unwrap();
+    assert_eq!(status.next().await.unwrap(), Some(Status::Online));
+
+    let session = client.session(&uuid).await.unwrap();
+    assert_eq!(session.state, SessionState::Online);
+    assert_eq!(session.device.as_str(), "session-online");
+
+    // A new start isn't debounced by the finished session
+    let restarted = client
+        .start(&DeviceId::new("session-online").unwrap(), true)
+        .await
+        .unwrap();
+    assert!(restarted.boot);
+    assert_ne!(restarted.uuid.unwrap(), uuid);
+}
+
+#[tokio::test]
+async fn late_watchers_get_the_result() {
+    let harness = Harness::start(false).await;
+    harness.create(device("session-late", 68)).await;
+    harness.probe.set("10.0.0.68", Reply::Online);
+    let client = Client::new(harness.url(""));
+    let uuid = harness.ping_device("session-late").await;
+
+    timeout(Duration::from_secs(5), async {
+        while client.session(&uuid).await.unwrap().state == SessionState::Booting {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("device online in time");
+
+    // Watchers joining after the device came online still get the result
+    for _ in 0..3 {
+        let mut status = client.status(&uuid).await.unwrap();
+        assert_eq!(status.next().await.unwrap(), Some(Status::Eta(0)));
+        assert_eq!(status.next().await.unwrap(), Some(Status::Online));
+    }
+}
+
+#[tokio::test]
+async fn concurrent_starts_share_one_session() {
+    let harness = Harness::start(false).await;
+    harness.create(device("concurrent", 62)).await;
+
+    let starts = (0..20).map(|_| {
+        let request = harness
+            .client
+            .post(harness.url("/start/concurrent"))
+            .json(&json!({ "ping": true }))
+            .send();
+        async move { request.await.unwrap().json::<Value>().await.unwrap() }
+    });
+    let responses = futures_util::future::join_all(starts).await;
+
+    // Only the start that reserved the session sends the magic packet
+    assert_eq!(harness.sent.packets().len(), 1);
+    let booted = responses.iter().filter(|response| response["boot"] == true);
+    assert_eq!(booted.count(), 1);
+
+    let uuid = &responses[0]["uuid"];
+    assert!(uuid.is_string());
+    assert!(responses.iter().all(|response| &response["uuid"] == uuid));
+    let uuid = uuid.as_str().unwrap();
+
+    let client = Client::new(harness.url(""));
+    let sessions = client.sessions().await.unwrap();
+    let booting = sessions
+        .iter()
+        .filter(|session| session.device.as_str() == "concurrent")
+        .collect::<Vec<_>>();
+    assert_eq!(booting.len(), 1);
+    assert_eq!(booting[0].uuid, uuid);
+
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(harness.probe.concurrent("10.0.0.62"), 1);
+
+    // Every waiter is told once the device is online
+    let mut watchers = vec![];
+    for _ in 0..5 {
+        let mut status = client.status(uuid).await.unwrap();
+        assert_eq!(status.next().await.unwrap(), Some(Status::Eta(0)));
+        watchers.push(status);
+    }
+    harness.probe.set("10.0.0.62", Reply::Online);
+    for mut status in watchers {
+        let online = timeout(Duration::from_secs(5), status.next())
+            .await
+            .unwrap();
+        assert_eq!(online.unwrap(), Some(Status::Online));
+    }
+    assert_eq!(
+        client.session(uuid).await.unwrap().state,
+        SessionState::Online
+    );
+}
+
+#[tokio::test]
+async fn concurrent_starts_of_different_devices_are_independent() {
+    let harness = Harness::start(false).await;
+    for host in 63..68 {
+        harness
+            .create(device(&format!("parallel-{host}"), host))
+            .await;
+    }
+
+    let starts = (63..68).map(|host| {
+        let request = harness
+            .client
+            .post(harness.url(&format!("/start/parallel-{host}")))
+            .json(&json!({ "ping": true }))
+            .send();
+        async move { request.await.unwrap().json::<Value>().await.unwrap() }
+    });
+    let responses = futures_util::future::join_all(starts).await;
+
+    let mut uuids = responses
+        .iter()
+        .map(|response| response["uuid"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    uuids.sort();
+    uuids.dedup();
+    assert_eq!(uuids.len(), 5);
+
+    sleep(Duration::from_millis(100)).await;
+    for host in 63..68 {
+        assert_eq!(harness.probe.concurrent(&format!("10.0.0.{host}")), 1);
+    }
+}
+
+#[tokio::test]
+async fn progress_reports_milestones() {
+    let harness = Harness::start_with(false, |config| {
+        config.pingprobes = vec![Check::Arp, Check::Icmp, Check::Tcp(22)];
+    })
+    .await;
+    harness.create(device("milestones", 69)).await;
+    harness
+        .probe
+        .set_check("10.0.0.69", Check::Arp, Reply::Online);
+    let client = Client::new(harness.url(""));
+    let uuid = harness.ping_device("milestones").await;
+
+    let mut status = client.progress(&uuid).await.unwrap();
+    assert_eq!(status.next().await.unwrap(), Some(Status::Eta(0)));
+    let progress = progress_until(&mut status, |progress| !progress.milestones.is_empty()).await;
+    assert_eq!(progress.state, SessionState::Booting);
+    assert_eq!(progress.milestones[0].check, Check::Arp);
+    assert_eq!(progress.percent, None);
+    assert!(progress.attempts >= 1);
+
+    harness
+        .probe
+        .set_check("10.0.0.69", Check::Icmp, Reply::Online);
+    let progress = progress_until(&mut status, |progress| progress.milestones.len() == 2).await;
+    assert_eq!(progress.state, SessionState::Booting);
+    assert_eq!(progress.milestones[1].check, Check::Icmp);
+
+    harness
+        .probe
+        .set_check("10.0.0.69", Check::Tcp(22), Reply::Online);
+    let progress = progress_until(&mut status, |progress| {
+        progress.state != SessionState::Booting
+    })
+    .await;
+    assert_eq!(progress.state, SessionState::Online);
+    let checks = progress
+        .milestones
+        .iter()
+        .map(|milestone| milestone.check)
+        .collect::<Vec<_>>();
+    assert_eq!(checks, [Check::Arp, Check::Icmp, Check::Tcp(22)]);
+    assert_eq!(status.next().await.unwrap(), Some(Status::Online));
+}
+
+#[tokio::test]
+async fn progress_reports_the_probe_error() {
+    let harness = Harness::start(false).await;
+    harness.create(device("progress-error", 70)).await;
+    harness.probe.set("10.0.0.70", Reply::Error);
+    let client = Client::new(harness.url(""));
+    let uuid = harness.ping_device("progress-error").await;
+
+    let mut status = client.progress(&uuid).await.unwrap();
+    assert_eq!(status.next().await.unwrap(), Some(Status::Eta(0)));
+    let progress = progress_until(&mut status, |progress| {
+        progress.state != SessionState::Booting
+    })
+    .await;
+    assert_eq!(progress.state, SessionState::Error);
+    assert_eq!(progress.error.as_deref(), Some("io: probe failed"));
+    assert_eq!(status.next().await.unwrap(), Some(Status::Error));
+}
+
+#[tokio::test]
+async fn progress_reports_failed_checks_while_booting() {
+    let harness = Harness::start_with(false, |config| {
+        config.pingprobes = vec![Check::Icmp, Check::Tcp(22)];
+    })
+    .await;
+    harness.create(device("progress-failure", 71)).await;
+    harness
+        .probe
+        .set_check("10.0.0.71", Check::Icmp, Reply::Online);
+    let client = Client::new(harness.url(""));
+    let uuid = harness.ping_device("progress-failure").await;
+
+    let mut status = client.progress(&uuid).await.unwrap();
+    assert_eq!(status.next().await.unwrap(), Some(Status::Eta(0)));
+    let progress = progress_until(&mut status, |progress| progress.failure.is_some()).await;
+    assert_eq!(progress.state, SessionState::Booting);
+    assert_eq!(progress.error, None);
+    let failure = progress.failure.unwrap();
+    assert_eq!(failure.check, Check::Tcp(22));
+    assert_eq!(failure.reason, "offline");
+
+    harness
+        .probe
+        .set_check("10.0.0.71", Check::Tcp(22), Reply::Online);
+    let progress = progress_until(&mut status, |progress| {
+        progress.state != SessionState::Booting
+    })
+    .await;
+    assert_eq!(progress.state, SessionState::Online);
+    assert_eq!(progress.failure, None);
+    assert_eq!(status.next().await.unwrap(), Some(Status::Online));
+}
+
+#[tokio::test]
+async fn arp_check_of_ipv6_devices_is_rejected() {
+    let harness = Harness::start_with(false, |config| {
+        config.pingprobes = vec![Check::Arp, Check::Icmp];
+    })
+    .await;
+    harness
+        .create(json!({
+            "id": "ipv6-arp",
+            "mac": "AA:BB:CC:DD:EE:48",
+            "ip": "fd00::72/64",
+        }))
+        .await;
+
+    let response = harness
+        .client
+        .post(harness.url("/start/ipv6-arp"))
+        .json(&json!({ "ping": true }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(harness.sent.packets().is_empty());
+    assert!(Client::new(harness.url(""))
+        .sessions()
+        .await
+        .unwrap()
+        .iter()
+        .all(|session| session.device.as_str() != "ipv6-arp"));
+}
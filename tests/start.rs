@@ -0,0 +1,300 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{device, magic_packet, Harness, Reply, SECRET};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::time::timeout;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use webol::config::{Agent, Limit};
+
+type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn status(harness: &Harness, uuid: &str) -> Socket {
+    let (mut socket, _) = connect_async(harness.ws_url("/status")).await.unwrap();
+    socket.send(Message::Text(uuid.to_string())).await.unwrap();
+    socket
+}
+
+async fn next(socket: &mut Socket) -> String {
+    let message = timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("status message in time")
+        .unwrap()
+        .unwrap();
+    message.into_text().unwrap()
+}
+
+#[tokio::test]
+async fn start_without_ping() {
+    let harness = Harness::start(false).await;
+    harness.create(device("plain", 40)).await;
+
+    let response = harness.start_device("plain", false).await;
+    assert_eq!(response["id"], "plain");
+    assert_eq!(response["boot"], true);
+    assert_eq!(response["uuid"], Value::Null);
+
+    let packets = harness.sent.packets();
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].target, "10.0.0.255:9".parse().unwrap());
+    assert_eq!(
+        packets[0].buffer,
+        magic_packet([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 40])
+    );
+}
+
+#[tokio::test]
+async fn start_is_relayed_to_agent() {
+    let agent = Harness::start(true).await;
+    let harness = Harness::start_with(false, |config| {
+        config.agents = vec![Agent {
+            name: "remote".to_string(),
+            url: agent.url(""),
+            secret: Some(SECRET.to_string()),
+            ca: None,
+            cert: None,
+            key: None,
+        }];
+    })
+    .await;
+    let mut relayed = device("relayed", 43);
+    relayed["via"] = json!("remote");
+    harness.create(relayed).await;
+
+    let response = harness.start_device("relayed", false).await;
+    assert_eq!(response["boot"], true);
+    assert_eq!(response["via"], "remote");
+
+    assert!(harness.sent.packets().is_empty());
+    let packets = agent.sent.packets();
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].target, "10.0.0.255:9".parse().unwrap());
+    assert_eq!(
+        packets[0].buffer,
+        magic_packet([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 43])
+    );
+}
+
+#[tokio::test]
+async fn start_with_ping_reports_online() {
+    let harness = Harness::start(false).await;
+    harness.create(device("pinged", 41)).await;
+    harness.probe.set("10.0.0.41", Reply::Offline);
+
+    let response = harness.start_device("pinged", true).await;
+    let uuid = response["uuid"]
+        .as_str()
+        .expect("uuid for ping")
+        .to_string();
+    assert_eq!(harness.sent.packets().len(), 1);
+
+    let mut socket = status(&harness, &uuid).await;
+    assert_eq!(next(&mut socket).await, format!("eta_0_{uuid}"));
+
+    harness.probe.set("10.0.0.41", Reply::Online);
+    assert_eq!(next(&mut socket).await, format!("start_{uuid}"));
+    assert!(matches!(
+        timeout(Duration::from_secs(5), socket.next())
+            .await
+            .unwrap(),
+        Some(Ok(Message::Close(_))) | None
+    ));
+}
+
+#[tokio::test]
+async fn start_with_ping_reports_errors() {
+    let harness = Harness::start(false).await;
+    harness.create(device("failing", 42)).await;
+    harness.probe.set("10.0.0.42", Reply::Offline);
+
+    let response = harness.start_device("failing", true).await;
+    let uuid = response["uuid"].as_str().unwrap().to_string();
+
+    let mut socket = status(&harness, &uuid).await;
+    assert_eq!(next(&mut socket).await, format!("eta_0_{uuid}"));
+
+    harness.probe.set("10.0.0.42", Reply::Error);
+    assert_eq!(next(&mut socket).await, format!("error_{uuid}"));
+}
+
+#[tokio::test]
+async fn status_of_unknown_uuid() {
+    let harness = Harness::start(false).await;
+
+    let mut socket = status(&harness, "unknown").await;
+    assert_eq!(next(&mut socket).await, "notfound_unknown");
+}
+
+#[tokio::test]
+async fn status_rejects_binary_messages() {
+    let harness = Harness::start(false).await;
+    let (mut socket, _) = connect_async(harness.ws_url("/status")).await.unwrap();
+    socket.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+
+    let message = timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("close frame in time")
+        .unwrap()
+        .unwrap();
+    let Message::Close(Some(frame)) = message else {
+        panic!("expected a close frame, got {message:?}");
+    };
+    assert_eq!(u16::from(frame.code), 1003);
+}
+
+#[tokio::test]
+async fn ping_needs_an_ip() {
+    let harness = Harness::start(false).await;
+    harness
+        .create(json!({
+            "id": "no-ip",
+            "mac": "AA:BB:CC:DD:EE:43",
+            "broadcast_addr": "10.0.0.255:9",
+        }))
+        .await;
+
+    let response = harness
+        .client
+        .post(harness.url("/start/no-ip"))
+        .json(&json!({ "ping": true }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn repeated_start_is_debounced() {
+    let harness = Harness::start(false).await;
+    harness.create(device("debounced", 44)).await;
+
+    let first = harness.start_device("debounced", true).await;
+    assert_eq!(first["boot"], true);
+    assert_eq!(first["eta"], 0);
+
+    let second = harness.start_device("debounced", false).await;
+    assert_eq!(second["boot"], false);
+    assert_eq!(second["uuid"], first["uuid"]);
+    assert_eq!(second["eta"], 0);
+    assert_eq!(harness.sent.packets().len(), 1);
+
+    let response = harness
+        .client
+        .post(harness.url("/start/debounced"))
+        .json(&json!({ "force": true }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.json::<Value>().await.unwrap()["boot"], true);
+    assert_eq!(harness.sent.packets().len(), 2);
+}
+
+#[tokio::test]
+async fn starts_are_rate_limited() {
+    let harness = Harness::start_with(false, |config| {
+        config.ratelimit.device = Some(Limit {
+            requests: 2,
+            seconds: 60,
+        });
+        config.ratelimit.client = Some(Limit {
+            requests: 3,
+            seconds: 60,
+        });
+    })
+    .await;
+    harness.create(device("limited", 45)).await;
+    harness.create(device("limited-other", 46)).await;
+
+    harness.start_device("limited", false).await;
+    harness.start_device("limited", false).await;
+    let response = harness
+        .client
+        .post(harness.url("/start/limited"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 429);
+    let retry_after: u64 = response.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((59..=60).contains(&retry_after));
+    assert_eq!(harness.sent.packets().len(), 2);
+
+    // The device limit is per device, the client limit counts all starts
+    let response = harness
+        .client
+        .post(harness.url("/start/limited-other"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 429);
+}
+
+#[tokio::test]
+async fn get_start_can_be_disabled() {
+    let harness = Harness::start_with(false, |config| config.wake.get = false).await;
+    harness.create(device("no-get", 47)).await;
+
+    let response = harness
+        .client
+        .get(harness.url("/start/no-get"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["allow"], "POST");
+    assert!(harness.sent.packets().is_empty());
+
+    harness.start_device("no-get", false).await;
+    assert_eq!(harness.sent.packets().len(), 1);
+}
+
+#[tokio::test]
+async fn wake_tokens_are_single_use() {
+    let harness = Harness::start(true).await;
+    harness.create(device("tokened", 48)).await;
+
+    let response = harness
+        .client
+        .post(harness.url("/start/tokened/token"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+
+    let issued: Value = harness
+        .client
+        .post(harness.url("/start/tokened/token"))
+        .header("authorization", SECRET)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let url = harness.url(issued["url"].as_str().unwrap());
+
+    // Opening the url only shows the confirmation page
+    let page = harness.client.get(&url).send().await.unwrap();
+    assert_eq!(page.status(), 200);
+    assert!(page.text().await.unwrap().contains("Start tokened"));
+    assert!(harness.sent.packets().is_empty());
+
+    let response = harness.client.post(&url).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.json::<Value>().await.unwrap()["id"], "tokened");
+    assert_eq!(harness.sent.packets().len(), 1);
+
+    for request in [harness.client.post(&url), harness.client.get(&url)] {
+        assert_eq!(request.send().await.unwrap().status(), 403);
+    }
+
+    let forged = url.replace("tokened", "debounced");
+    let response = harness.client.post(&forged).send().await.unwrap();
+    assert_eq!(response.status(), 403);
+    assert_eq!(harness.sent.packets().len(), 1);
+}